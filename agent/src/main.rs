@@ -1,7 +1,7 @@
 use anyhow::Result;
 use lazy_static::lazy_static;
 use ollama::Ollama;
-use rand::{thread_rng, Rng};
+use rand::thread_rng;
 use rig::{
     agent::AgentBuilder,
     completion::{Chat, Message, ToolDefinition},
@@ -10,19 +10,23 @@ use rig::{
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 use shield_circuit::{
-    pool::{AnonymityPool, Note},
+    pool::{AnonymityPool, Note, NoteWitness, Payout, SpendingKey, Withdrawal, NATIVE_ASSET},
+    zk::{self, WithdrawProvingKey, WithdrawVerifyingKey},
     Hash,
 };
+use std::collections::HashMap;
 use std::io::{self, Write};
-use std::{collections::HashMap, ops::AddAssign};
 use tokio::sync::Mutex;
 
 mod ollama;
 
 lazy_static! {
+    static ref KEYS: (WithdrawProvingKey, WithdrawVerifyingKey) =
+        zk::setup(&mut thread_rng()).expect("failed to set up the withdrawal circuit");
     static ref NOTES: Mutex<HashMap<Hash, Note>> = Mutex::new(HashMap::new());
-    static ref POOL: Mutex<AnonymityPool> = Mutex::new(AnonymityPool::new());
-    static ref TOPIC: Mutex<u64> = Mutex::new(0);
+    static ref WITNESSES: Mutex<HashMap<Hash, NoteWitness>> = Mutex::new(HashMap::new());
+    static ref POOL: Mutex<AnonymityPool> = Mutex::new(AnonymityPool::new(&KEYS.1));
+    static ref SPENDING_KEY: SpendingKey = SpendingKey::random(&mut thread_rng());
 }
 
 const EXIT_KEYWORDS: [&str; 3] = ["exit", "abort", "quit"];
@@ -31,6 +35,24 @@ const EXIT_KEYWORDS: [&str; 3] = ["exit", "abort", "quit"];
 enum WalletError {
     #[error("Note not found: {0}")]
     NotFound(String),
+    #[error("Invalid argument: {0}")]
+    InvalidArgument(String),
+    #[error("Pool error: {0}")]
+    Pool(#[from] shield_circuit::Error),
+}
+
+/// Parses the tool argument `name`, or returns `default` if it is missing.
+fn optional_number(
+    args: &HashMap<String, String>,
+    name: &str,
+    default: u64,
+) -> Result<u64, WalletError> {
+    match args.get(name) {
+        Some(value) => value
+            .parse::<u64>()
+            .map_err(|_| WalletError::InvalidArgument(value.clone())),
+        None => Ok(default),
+    }
 }
 
 #[derive(Deserialize, Serialize)]
@@ -58,15 +80,20 @@ impl Tool for Status {
 
         lines.push("Unshielded accounts:".to_string());
         let pool = POOL.lock().await;
-        for (account, amount) in pool.balances() {
-            lines.push(format!("{}: {}", account, amount));
+        for ((account, asset), amount) in pool.balances() {
+            lines.push(format!("{} ({}): {}", account, asset, amount));
         }
         lines.push(String::new());
 
         lines.push("Shielded accounts:".to_string());
         let notes = NOTES.lock().await;
-        for (_, note) in notes.iter() {
-            lines.push(format!("{}: {}", note.recipiant(), AnonymityPool::amount()));
+        for (nullifier, note) in notes.iter() {
+            lines.push(format!(
+                "{} ({}): {}",
+                nullifier.to_hex(),
+                note.asset(),
+                note.amount()
+            ));
         }
 
         let output = lines.join("\n");
@@ -92,9 +119,9 @@ impl Tool for Deposit {
             parameters: json!({
                 "type": "object",
                 "properties": {
-                    "recipient": {
+                    "asset": {
                         "type": "number",
-                        "description": "The recipient address. The address that will withdraw the amount from the pool."
+                        "description": "The asset id to deposit. Defaults to the native coin (0)."
                     },
                 }
             }),
@@ -102,22 +129,24 @@ impl Tool for Deposit {
     }
 
     async fn call(&self, arg: Self::Args) -> Result<Self::Output, Self::Error> {
-        let secret = {
-            let rng = &mut thread_rng();
-            rng.gen::<u64>()
-        };
         let sender = AnonymityPool::account();
-        let recipient_value = arg.get("recipient").unwrap();
-        let recipient = recipient_value.parse::<u64>().unwrap();
+        let asset = optional_number(&arg, "asset", NATIVE_ASSET)?;
 
         let mut pool = POOL.lock().await;
-        let mut topic = TOPIC.lock().await;
+        let mut note = {
+            let rng = &mut thread_rng();
+            Note::random(rng, pool.amount(), asset, SPENDING_KEY.public_key())
+        };
+
         let mut notes = NOTES.lock().await;
-        let note = pool.deposit(sender, secret, *topic, recipient);
+        let mut witnesses = WITNESSES.lock().await;
+        let (epoch, index) = pool.deposit_commitment(sender, asset, note.commitment())?;
+        let witness = pool.witness(epoch, index)?;
+        note.set_epoch(epoch);
         let nullifier = note.nullifier();
         notes.insert(nullifier.clone(), note);
+        witnesses.insert(nullifier.clone(), witness);
 
-        topic.add_assign(1);
         Ok(nullifier.to_hex())
     }
 }
@@ -133,7 +162,7 @@ impl Tool for Withdraw {
     type Output = String;
 
     async fn definition(&self, _prompt: String) -> ToolDefinition {
-        let desc = "Withdraw the amount from privacy/anonymity pool into the main address.";
+        let desc = "Withdraw the amount from privacy/anonymity pool into the recipient address.";
         ToolDefinition {
             name: Self::NAME.to_string(),
             description: desc.to_string(),
@@ -144,22 +173,62 @@ impl Tool for Withdraw {
                         "type": "string",
                         "description": "The nullifier value. Unique identifier of the note related to previous deposit."
                     },
+                    "recipient": {
+                        "type": "number",
+                        "description": "The recipient address. The address that receives the amount from the pool."
+                    },
+                    "relayer": {
+                        "type": "number",
+                        "description": "The relayer address submitting the withdrawal. Defaults to none (0)."
+                    },
+                    "fee": {
+                        "type": "number",
+                        "description": "The part of the amount paid to the relayer. Defaults to 0."
+                    },
                 }
             }),
         }
     }
 
     async fn call(&self, arg: Self::Args) -> Result<Self::Output, Self::Error> {
-        let nullifier_value = arg.get("nullifier").unwrap();
-        let nullifier = Hash::from_hex(nullifier_value.clone());
+        let nullifier_value = arg
+            .get("nullifier")
+            .ok_or(WalletError::InvalidArgument("nullifier".to_string()))?;
+        let nullifier = Hash::from_hex(nullifier_value.clone())?;
+        let recipient_value = arg
+            .get("recipient")
+            .ok_or(WalletError::InvalidArgument("recipient".to_string()))?;
+        let recipient = recipient_value
+            .parse::<u64>()
+            .map_err(|_| WalletError::InvalidArgument(recipient_value.clone()))?;
+        let relayer = optional_number(&arg, "relayer", 0)?;
+        let fee = optional_number(&arg, "fee", 0)?;
 
         let mut pool = POOL.lock().await;
         let notes = NOTES.lock().await;
-        let res = match notes.get(&nullifier) {
-            Some(note) => pool.withdraw(note.clone()),
-            None => return Err(WalletError::NotFound(nullifier.to_hex())),
+        let mut witnesses = WITNESSES.lock().await;
+        let (note, witness) = match (notes.get(&nullifier), witnesses.get_mut(&nullifier)) {
+            (Some(note), Some(witness)) => (note, witness),
+            _ => return Err(WalletError::NotFound(nullifier.to_hex())),
+        };
+        for commitment in pool.commitments(note.epoch(), witness.size())? {
+            witness.append(commitment)?;
+        }
+        let path = witness.path();
+        let payout = Payout::new(recipient).with_relayer(relayer, fee);
+        let proof = {
+            let rng = &mut thread_rng();
+            note.prove(&SPENDING_KEY, &path, &payout, &KEYS.0, rng)?
+        };
+        let withdrawal = Withdrawal {
+            asset: note.asset(),
+            epoch: note.epoch(),
+            root: path.construct_root(),
+            nullifier_hash: note.nullifier(),
+            payout,
         };
-        Ok(format!("Result: {}", res))
+        pool.withdraw(&withdrawal, &proof)?;
+        Ok("Result: true".to_string())
     }
 }
 
@@ -179,8 +248,10 @@ async fn main() -> Result<(), anyhow::Error> {
 
     println!("Recommended commands:");
     println!("Show me wallet status. Call tool: show_wallet_status");
-    println!("Deposit into the pool with recipient = 456. Call tool: deposit");
-    println!("Withdraw from the pool with nullifier = [nullifier]. Call tool: withdraw");
+    println!("Deposit into the pool. Call tool: deposit");
+    println!(
+        "Withdraw from the pool with nullifier = [nullifier] and recipient = 456. Call tool: withdraw"
+    );
 
     let inp = io::stdin();
     print!("> ");
@@ -1,17 +1,23 @@
 use lazy_static::lazy_static;
-use rand::{thread_rng, Rng};
+use rand::thread_rng;
 use shield_circuit::{
-    pool::{AnonymityPool, Note},
+    manager::PoolManager,
+    pool::{AnonymityPool, Note, NoteWitness, Payout, SpendingKey, Withdrawal, NATIVE_ASSET},
+    zk::{self, WithdrawProvingKey, WithdrawVerifyingKey},
     Hash,
 };
+use std::collections::{HashMap, HashSet};
 use std::sync::Mutex;
-use std::{collections::HashMap, ops::AddAssign};
 use tauri::Manager;
 
+// Commands that hold more than one lock take them as POOL, NOTES, WITNESSES.
 lazy_static! {
+    static ref KEYS: (WithdrawProvingKey, WithdrawVerifyingKey) =
+        zk::setup(&mut thread_rng()).expect("failed to set up the withdrawal circuit");
     static ref NOTES: Mutex<HashMap<Hash, Note>> = Mutex::new(HashMap::new());
-    static ref POOL: Mutex<AnonymityPool> = Mutex::new(AnonymityPool::new());
-    static ref TOPIC: Mutex<u64> = Mutex::new(0);
+    static ref WITNESSES: Mutex<HashMap<Hash, NoteWitness>> = Mutex::new(HashMap::new());
+    static ref POOL: Mutex<PoolManager> = Mutex::new(PoolManager::new(&KEYS.1));
+    static ref SPENDING_KEY: SpendingKey = SpendingKey::random(&mut thread_rng());
 }
 
 #[tauri::command]
@@ -20,39 +26,60 @@ fn get_default_account() -> String {
 }
 
 #[tauri::command]
-fn get_default_amount() -> String {
-    AnonymityPool::amount().to_string()
+fn get_denominations() -> Result<Vec<u64>, String> {
+    let pool = match POOL.lock() {
+        Ok(pool) => pool,
+        Err(e) => return Err(e.to_string()),
+    };
+    Ok(pool.denominations())
 }
 
 #[tauri::command]
-fn get_balance(account: u64) -> Result<String, String> {
+fn get_balance(account: u64, asset: u64) -> Result<String, String> {
     let pool = match POOL.lock() {
         Ok(pool) => pool,
         Err(e) => return Err(e.to_string()),
     };
-    Ok(pool.get_balance(account).to_string())
+    Ok(pool.get_balance(account, asset).to_string())
 }
 
 #[tauri::command]
-fn deposit(recipiant: u64) -> Result<String, String> {
-    let mut rng = thread_rng();
-    let secret = rng.gen::<u64>();
-    let mut topic = match TOPIC.lock() {
-        Ok(topic) => topic,
+fn deposit(denomination: u64, asset: u64) -> Result<String, String> {
+    let mut pool = match POOL.lock() {
+        Ok(pool) => pool,
         Err(e) => return Err(e.to_string()),
     };
     let mut notes = match NOTES.lock() {
         Ok(notes) => notes,
         Err(e) => return Err(e.to_string()),
     };
-    let mut pool = match POOL.lock() {
-        Ok(pool) => pool,
+    let mut witnesses = match WITNESSES.lock() {
+        Ok(witnesses) => witnesses,
+        Err(e) => return Err(e.to_string()),
+    };
+    let mut note = Note::random(
+        &mut thread_rng(),
+        denomination,
+        asset,
+        SPENDING_KEY.public_key(),
+    );
+    let sender = AnonymityPool::account();
+    let commitment = note.commitment();
+    let (epoch, index) = match pool.deposit_commitment(sender, denomination, asset, commitment) {
+        Ok(position) => position,
         Err(e) => return Err(e.to_string()),
     };
-    let note = pool.deposit(AnonymityPool::account(), secret, *topic, recipiant);
+    let witness = match pool
+        .pool(denomination)
+        .and_then(|pool| pool.witness(epoch, index))
+    {
+        Ok(witness) => witness,
+        Err(e) => return Err(e.to_string()),
+    };
+    note.set_epoch(epoch);
     let nullifier = note.nullifier();
     notes.insert(nullifier.clone(), note);
-    topic.add_assign(1);
+    witnesses.insert(nullifier.clone(), witness);
 
     Ok(nullifier.to_hex())
 }
@@ -67,7 +94,7 @@ fn get_notes() -> Result<HashMap<Hash, Note>, String> {
 }
 
 #[tauri::command]
-fn get_nullifiers() -> Result<HashMap<Hash, bool>, String> {
+fn get_nullifiers() -> Result<HashSet<Hash>, String> {
     let pool = match POOL.lock() {
         Ok(pool) => pool,
         Err(e) => return Err(e.to_string()),
@@ -76,7 +103,13 @@ fn get_nullifiers() -> Result<HashMap<Hash, bool>, String> {
 }
 
 #[tauri::command]
-fn withdraw(nullifier: Hash) -> Result<bool, String> {
+fn withdraw(
+    nullifier: Hash,
+    denomination: u64,
+    recipient: u64,
+    relayer: u64,
+    fee: u64,
+) -> Result<bool, String> {
     let mut pool = match POOL.lock() {
         Ok(pool) => pool,
         Err(e) => return Err(e.to_string()),
@@ -85,12 +118,43 @@ fn withdraw(nullifier: Hash) -> Result<bool, String> {
         Ok(pool) => pool,
         Err(e) => return Err(e.to_string()),
     };
-    let res = match notes.get(&nullifier) {
-        Some(note) => pool.withdraw(note.clone()),
-        None => return Err("Not Found".to_string()),
+    let mut witnesses = match WITNESSES.lock() {
+        Ok(witnesses) => witnesses,
+        Err(e) => return Err(e.to_string()),
     };
-
-    Ok(res)
+    let (note, witness) = match (notes.get(&nullifier), witnesses.get_mut(&nullifier)) {
+        (Some(note), Some(witness)) if note.amount() == denomination => (note, witness),
+        _ => return Err("Not Found".to_string()),
+    };
+    let commitments = match pool
+        .pool(denomination)
+        .and_then(|pool| pool.commitments(note.epoch(), witness.size()))
+    {
+        Ok(commitments) => commitments,
+        Err(e) => return Err(e.to_string()),
+    };
+    for commitment in commitments {
+        if let Err(e) = witness.append(commitment) {
+            return Err(e.to_string());
+        }
+    }
+    let path = witness.path();
+    let payout = Payout::new(recipient).with_relayer(relayer, fee);
+    let proof = match note.prove(&SPENDING_KEY, &path, &payout, &KEYS.0, &mut thread_rng()) {
+        Ok(proof) => proof,
+        Err(e) => return Err(e.to_string()),
+    };
+    let withdrawal = Withdrawal {
+        asset: note.asset(),
+        epoch: note.epoch(),
+        root: path.construct_root(),
+        nullifier_hash: note.nullifier(),
+        payout,
+    };
+    match pool.withdraw(denomination, &withdrawal, &proof) {
+        Ok(()) => Ok(true),
+        Err(e) => Err(e.to_string()),
+    }
 }
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
@@ -104,7 +168,7 @@ pub fn run() {
             get_notes,
             get_balance,
             get_nullifiers,
-            get_default_amount,
+            get_denominations,
             get_default_account,
         ])
         .setup(|app| {
@@ -121,8 +185,15 @@ pub fn run() {
 
 #[test]
 fn test_multiple_deposit_withdraw() {
-    let n1 = deposit(1).unwrap();
-    let n2 = deposit(2).unwrap();
-    assert_eq!(withdraw(Hash::from_hex(n1)).unwrap(), true);
-    assert_eq!(withdraw(Hash::from_hex(n2)).unwrap(), true);
+    let n1 = deposit(100, NATIVE_ASSET).unwrap();
+    let n2 = deposit(1000, NATIVE_ASSET).unwrap();
+    assert!(withdraw(Hash::from_hex(n1.clone()).unwrap(), 1000, 1, 0, 0).is_err());
+    assert_eq!(
+        withdraw(Hash::from_hex(n1).unwrap(), 100, 1, 0, 0).unwrap(),
+        true
+    );
+    assert_eq!(
+        withdraw(Hash::from_hex(n2).unwrap(), 1000, 2, 3, 10).unwrap(),
+        true
+    );
 }
@@ -0,0 +1,144 @@
+use crate::{hash_two, Field, Hash};
+use sha3::Digest;
+use std::marker::PhantomData;
+
+/// Domain tags of every hash in the scheme. Each one is absorbed as a leading
+/// 32-byte big-endian word (a field element for Poseidon), so a hash of one
+/// kind can never be passed off as a hash of another.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum Domain {
+    PublicKey = 1,
+    Nullifier = 2,
+    Leaf = 3,
+    Node = 4,
+    Empty = 5,
+    Commitment = 6,
+}
+
+impl Domain {
+    pub fn tag(self) -> u64 {
+        self as u64
+    }
+
+    pub fn to_field(self) -> Field {
+        Field::from(self.tag())
+    }
+}
+
+/// Hash functions of a Merkle tree: how leaf values enter the tree, how two
+/// children are combined, and what an empty leaf is.
+pub trait MerkleHasher {
+    /// Tree node of the leaf value `leaf`.
+    fn hash_leaf(leaf: &Hash) -> Hash;
+
+    fn hash_node(left: &Hash, right: &Hash) -> Hash;
+
+    /// Tree node of an unused leaf; empty subtrees are built from it with
+    /// `hash_node`.
+    fn empty_leaf() -> Hash;
+}
+
+/// Domain-separated hashing over any digest:
+///
+/// - `leaf = D(LEAF || value)`
+/// - `node = D(NODE || left || right)`
+/// - `empty = D(EMPTY)`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Tagged<D>(PhantomData<D>);
+
+impl<D> Tagged<D>
+where
+    D: Digest,
+{
+    fn digest(domain: Domain, words: &[&Hash]) -> Hash {
+        let mut hasher = D::new();
+        hasher.update(domain.to_field().to_hash().inner());
+        for word in words {
+            hasher.update(word.inner());
+        }
+        let mut bytes = [0; 32];
+        bytes.copy_from_slice(&hasher.finalize());
+        Hash(bytes)
+    }
+}
+
+impl<D> MerkleHasher for Tagged<D>
+where
+    D: Digest,
+{
+    fn hash_leaf(leaf: &Hash) -> Hash {
+        Self::digest(Domain::Leaf, &[leaf])
+    }
+
+    fn hash_node(left: &Hash, right: &Hash) -> Hash {
+        Self::digest(Domain::Node, &[left, right])
+    }
+
+    fn empty_leaf() -> Hash {
+        Self::digest(Domain::Empty, &[])
+    }
+}
+
+/// Original untagged hashing: leaves are used as they are, nodes are
+/// `hash_two` and empty leaves are zero. Only kept for compatibility with
+/// trees built before domain separation.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Legacy<D>(PhantomData<D>);
+
+impl<D> MerkleHasher for Legacy<D>
+where
+    D: Digest,
+{
+    fn hash_leaf(leaf: &Hash) -> Hash {
+        leaf.clone()
+    }
+
+    fn hash_node(left: &Hash, right: &Hash) -> Hash {
+        hash_two::<D>(left.clone(), right.clone())
+    }
+
+    fn empty_leaf() -> Hash {
+        Hash::default()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Domain, Legacy, MerkleHasher, Tagged};
+    use crate::{
+        poseidon::{hash, Poseidon},
+        Field, Hash,
+    };
+    use sha3::Keccak256;
+
+    #[test]
+    fn tagged_poseidon_absorbs_domain_as_first_input() {
+        let left = Field::from(1);
+        let right = Field::from(2);
+        assert_eq!(
+            Tagged::<Poseidon>::hash_node(&left.to_hash(), &right.to_hash()),
+            hash(&[Domain::Node.to_field(), left, right]).to_hash()
+        );
+        assert_eq!(
+            Tagged::<Poseidon>::hash_leaf(&left.to_hash()),
+            hash(&[Domain::Leaf.to_field(), left]).to_hash()
+        );
+        assert_eq!(
+            Tagged::<Poseidon>::empty_leaf(),
+            hash(&[Domain::Empty.to_field()]).to_hash()
+        );
+    }
+
+    #[test]
+    fn leaves_and_nodes_should_not_collide() {
+        let left = Hash::default();
+        let right = Hash::default();
+        let node = Tagged::<Keccak256>::hash_node(&left, &right);
+        assert_ne!(Tagged::<Keccak256>::hash_leaf(&node), node);
+        assert_ne!(Tagged::<Keccak256>::empty_leaf(), Hash::default());
+
+        // Legacy hashing has no such separation.
+        assert_eq!(Legacy::<Keccak256>::hash_leaf(&node), node);
+    }
+}
@@ -1,7 +1,21 @@
-mod merkle;
+pub mod hasher;
+pub mod manager;
+pub mod merkle;
+pub mod mmr;
+pub mod note;
 pub mod pool;
-
-use serde::{Deserialize, Serialize};
+pub mod poseidon;
+pub mod store;
+pub mod zk;
+
+use ark_bn254::Fr;
+use ark_relations::r1cs::SynthesisError;
+use ark_std::{
+    rand::{CryptoRng, RngCore},
+    UniformRand,
+};
+use poseidon::{field_to_hash, hash_to_field};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use sha3::Digest;
 use std::error::Error as StdError;
 use std::fmt::{Display, Formatter, Result as FmtResult};
@@ -13,10 +27,10 @@ use rand::Rng;
 pub struct Hash([u8; 32]);
 
 impl Hash {
-    pub fn from_hex(s: String) -> Hash {
-        let mut bytes = [0; 32];
-        bytes.copy_from_slice(hex::decode(s).unwrap().as_slice());
-        Hash(bytes)
+    pub fn from_hex(s: String) -> Result<Hash, Error> {
+        let decoded = hex::decode(s).map_err(|_| Error::InvalidHex)?;
+        let bytes: [u8; 32] = decoded.try_into().map_err(|_| Error::InvalidHex)?;
+        Ok(Hash(bytes))
     }
 
     pub fn to_hex(self) -> String {
@@ -28,6 +42,66 @@ impl Hash {
     }
 }
 
+/// Element of the BN254 scalar field: the native type of the Poseidon hasher
+/// and of the withdrawal circuit. Encoded as a big-endian `Hash`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Field(Fr);
+
+impl Field {
+    /// Reads a big-endian field element, rejecting values above the modulus.
+    pub fn from_hash(hash: &Hash) -> Result<Field, Error> {
+        let field = hash_to_field(hash);
+        if field_to_hash(field) != *hash {
+            return Err(Error::NonCanonicalField);
+        }
+        Ok(Field(field))
+    }
+
+    /// Uniformly random element, used for keys and blinding factors.
+    pub fn random<R: RngCore + CryptoRng>(rng: &mut R) -> Self {
+        Field(Fr::rand(rng))
+    }
+
+    pub fn to_hash(self) -> Hash {
+        field_to_hash(self.0)
+    }
+
+    pub fn inner(&self) -> &Fr {
+        &self.0
+    }
+}
+
+impl From<u64> for Field {
+    fn from(value: u64) -> Self {
+        Field(Fr::from(value))
+    }
+}
+
+impl From<Fr> for Field {
+    fn from(value: Fr) -> Self {
+        Field(value)
+    }
+}
+
+impl From<Field> for Hash {
+    fn from(value: Field) -> Self {
+        value.to_hash()
+    }
+}
+
+impl Serialize for Field {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.to_hash().serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Field {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let hash = Hash::deserialize(deserializer)?;
+        Field::from_hash(&hash).map_err(serde::de::Error::custom)
+    }
+}
+
 #[cfg(test)]
 impl Hash {
     pub fn random<R: Rng>(rng: &mut R) -> Self {
@@ -45,10 +119,11 @@ pub fn to_bits(num: &[u8]) -> Vec<bool> {
     bits
 }
 
-pub fn num_to_bits_vec(num: u64) -> Vec<bool> {
+/// Little-endian bit decomposition of `num`, truncated to `len` bits.
+pub fn num_to_bits_vec(num: u64, len: usize) -> Vec<bool> {
     let bits = to_bits(&num.to_le_bytes());
 
-    bits[..u32::BITS as usize].to_vec()
+    bits[..len].to_vec()
 }
 
 fn next_index(i: u64) -> u64 {
@@ -78,19 +153,67 @@ pub fn hash_leaf<H: Digest>(preimage: Vec<u8>) -> Hash {
     Hash(bytes)
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Error {
     RootNotFound,
     NodesNotFound,
+    NonCanonicalField,
+    InvalidPath,
+    TreeFull,
+    LeafNotFound,
+    InsufficientBalance,
+    InvalidHex,
+    UnknownRoot,
+    UnknownEpoch,
+    UnknownDenomination,
+    NullifierSpent,
+    InvalidProof,
+    FeeTooHigh,
+    InvalidTransfer,
+    InvalidNote,
+    InvalidRange,
+    InvalidHashInput,
+    CheckpointNotFound,
+    CorruptStore,
+    Unsupported,
+    Storage(String),
+    Synthesis(SynthesisError),
 }
 
 impl StdError for Error {}
 
+impl From<SynthesisError> for Error {
+    fn from(e: SynthesisError) -> Self {
+        Self::Synthesis(e)
+    }
+}
+
 impl Display for Error {
     fn fmt(&self, f: &mut Formatter) -> FmtResult {
         match self {
             Self::RootNotFound => write!(f, "RootNotFound"),
             Self::NodesNotFound => write!(f, "NodesNotFound"),
+            Self::NonCanonicalField => write!(f, "NonCanonicalField"),
+            Self::InvalidPath => write!(f, "InvalidPath"),
+            Self::TreeFull => write!(f, "TreeFull"),
+            Self::LeafNotFound => write!(f, "LeafNotFound"),
+            Self::InsufficientBalance => write!(f, "InsufficientBalance"),
+            Self::InvalidHex => write!(f, "InvalidHex"),
+            Self::UnknownRoot => write!(f, "UnknownRoot"),
+            Self::UnknownEpoch => write!(f, "UnknownEpoch"),
+            Self::UnknownDenomination => write!(f, "UnknownDenomination"),
+            Self::NullifierSpent => write!(f, "NullifierSpent"),
+            Self::InvalidProof => write!(f, "InvalidProof"),
+            Self::FeeTooHigh => write!(f, "FeeTooHigh"),
+            Self::InvalidTransfer => write!(f, "InvalidTransfer"),
+            Self::InvalidNote => write!(f, "InvalidNote"),
+            Self::InvalidRange => write!(f, "InvalidRange"),
+            Self::InvalidHashInput => write!(f, "InvalidHashInput"),
+            Self::CheckpointNotFound => write!(f, "CheckpointNotFound"),
+            Self::CorruptStore => write!(f, "CorruptStore"),
+            Self::Unsupported => write!(f, "Unsupported"),
+            Self::Storage(e) => write!(f, "Storage: {}", e),
+            Self::Synthesis(e) => write!(f, "Synthesis: {}", e),
         }
     }
 }
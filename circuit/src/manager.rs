@@ -0,0 +1,299 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use crate::{
+    pool::{
+        pay_in, pay_withdrawal, AnonymityPool, Balances, ShieldedPool, Transfer, Withdrawal,
+        NATIVE_ASSET,
+    },
+    zk::{TransferProof, TransferVerifyingKey, WithdrawProof, WithdrawVerifyingKey},
+    Error, Hash,
+};
+
+/// Denominations of a default manager: 0.1, 1, 10 and 100 coins of 1000
+/// units.
+pub const DEFAULT_DENOMINATIONS: [u64; 4] = [100, 1_000, 10_000, 100_000];
+
+/// Fixed-denomination pools sharing one ledger. Each denomination has its
+/// own tree, nullifier set and root history, so a withdrawal only reveals
+/// the denomination of the note it spends. Deposits and withdrawals move
+/// funds on the manager's ledger.
+pub struct PoolManager {
+    pools: BTreeMap<u64, ShieldedPool>,
+    balances: Balances,
+    checkpoints: BTreeMap<u64, Balances>,
+}
+
+impl PoolManager {
+    pub fn new(verifying_key: &WithdrawVerifyingKey) -> Self {
+        Self::with_denominations(verifying_key, &DEFAULT_DENOMINATIONS)
+    }
+
+    /// Manager with one pool per distinct amount in `denominations`.
+    pub fn with_denominations(verifying_key: &WithdrawVerifyingKey, denominations: &[u64]) -> Self {
+        let pools: BTreeMap<u64, ShieldedPool> = denominations
+            .iter()
+            .map(|denomination| {
+                let pool = ShieldedPool::new(verifying_key).with_denomination(*denomination);
+                (*denomination, pool)
+            })
+            .collect();
+        let largest = pools.keys().last().copied().unwrap_or_default();
+        let mut balances = HashMap::new();
+        balances.insert((AnonymityPool::account(), NATIVE_ASSET), largest * 10);
+        Self {
+            pools,
+            balances,
+            checkpoints: BTreeMap::new(),
+        }
+    }
+
+    /// Accepts shielded transfers proven against `transfer_key` in every
+    /// pool.
+    pub fn with_transfer_key(mut self, transfer_key: &TransferVerifyingKey) -> Self {
+        self.pools = std::mem::take(&mut self.pools)
+            .into_iter()
+            .map(|(denomination, pool)| (denomination, pool.with_transfer_key(transfer_key)))
+            .collect();
+        self
+    }
+
+    /// Available denominations, smallest first.
+    pub fn denominations(&self) -> Vec<u64> {
+        self.pools.keys().copied().collect()
+    }
+
+    /// Pool of `denomination`, for paths, witnesses and roots of its notes.
+    pub fn pool(&self, denomination: u64) -> Result<&ShieldedPool, Error> {
+        self.pools
+            .get(&denomination)
+            .ok_or(Error::UnknownDenomination)
+    }
+
+    fn pool_mut(&mut self, denomination: u64) -> Result<&mut ShieldedPool, Error> {
+        self.pools
+            .get_mut(&denomination)
+            .ok_or(Error::UnknownDenomination)
+    }
+
+    /// Nullifiers of all spent notes, of every denomination.
+    pub fn nullifiers(&self) -> HashSet<Hash> {
+        self.pools
+            .values()
+            .flat_map(|pool| pool.nullifiers())
+            .collect()
+    }
+
+    pub fn balances(&self) -> Balances {
+        self.balances.clone()
+    }
+
+    pub fn get_balance(&self, account: u64, asset: u64) -> u64 {
+        *self.balances.get(&(account, asset)).unwrap_or(&0)
+    }
+
+    /// Credits `amount` of `asset` to `account`, e.g. when it is bridged in.
+    pub fn mint(&mut self, account: u64, asset: u64, amount: u64) {
+        *self.balances.entry((account, asset)).or_insert(0) += amount;
+    }
+
+    /// Inserts a commitment to a note of `denomination` units of `asset`
+    /// into the pool of that denomination, and moves that amount from
+    /// `sender`. Returns the epoch and leaf index of the commitment in that
+    /// pool.
+    pub fn deposit_commitment(
+        &mut self,
+        sender: u64,
+        denomination: u64,
+        asset: u64,
+        commitment: Hash,
+    ) -> Result<(u64, u64), Error> {
+        self.pool(denomination)?;
+        if self.get_balance(sender, asset) < denomination {
+            return Err(Error::InsufficientBalance);
+        }
+        let position = self.pool_mut(denomination)?.insert_commitment(commitment)?;
+        pay_in(&mut self.balances, sender, asset, denomination);
+        Ok(position)
+    }
+
+    /// Spends a note of `denomination`, paying that amount to the recipient
+    /// less the fee of the relayer.
+    pub fn withdraw(
+        &mut self,
+        denomination: u64,
+        withdrawal: &Withdrawal,
+        proof: &WithdrawProof,
+    ) -> Result<(), Error> {
+        self.pool_mut(denomination)?.spend(withdrawal, proof)?;
+        pay_withdrawal(&mut self.balances, withdrawal, denomination);
+        Ok(())
+    }
+
+    /// Splits or merges notes inside the pool of `denomination`, without
+    /// touching the ledger. Returns the epoch and leaf index of each new
+    /// commitment in that pool.
+    pub fn transfer(
+        &mut self,
+        denomination: u64,
+        transfer: &Transfer,
+        proof: &TransferProof,
+    ) -> Result<Vec<(u64, u64)>, Error> {
+        self.pool_mut(denomination)?.transfer(transfer, proof)
+    }
+
+    /// Saves the state of every pool and of the ledger under `id`.
+    pub fn checkpoint(&mut self, id: u64) {
+        for pool in self.pools.values_mut() {
+            pool.checkpoint(id);
+        }
+        self.checkpoints.insert(id, self.balances.clone());
+    }
+
+    /// Undoes every deposit and withdrawal of every pool made after
+    /// checkpoint `id`. Later checkpoints are dropped.
+    pub fn rewind_to(&mut self, id: u64) -> Result<(), Error> {
+        let balances = self
+            .checkpoints
+            .get(&id)
+            .cloned()
+            .ok_or(Error::CheckpointNotFound)?;
+        for pool in self.pools.values_mut() {
+            pool.rewind_to(id)?;
+        }
+        self.checkpoints.split_off(&(id + 1));
+        self.balances = balances;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::PoolManager;
+    use crate::{
+        note::prove_transfer,
+        pool::{AnonymityPool, Note, Payout, SpendingKey, Transfer, Withdrawal, NATIVE_ASSET},
+        zk, Error, Hash,
+    };
+    use rand::thread_rng;
+
+    #[test]
+    fn should_keep_denominations_apart() {
+        let rng = &mut thread_rng();
+        let (proving_key, verifying_key) = zk::setup(rng).unwrap();
+        let mut manager = PoolManager::with_denominations(&verifying_key, &[1_000, 100, 1_000]);
+        assert_eq!(manager.denominations(), vec![100, 1_000]);
+        let account = AnonymityPool::account();
+        let balance = manager.get_balance(account, NATIVE_ASSET);
+
+        let spending_key = SpendingKey::random(rng);
+        let small = Note::random(rng, 100, NATIVE_ASSET, spending_key.public_key());
+        let large = Note::random(rng, 1_000, NATIVE_ASSET, spending_key.public_key());
+        assert_eq!(
+            manager.deposit_commitment(account, 100, NATIVE_ASSET, small.commitment()),
+            Ok((0, 0))
+        );
+        assert_eq!(
+            manager.deposit_commitment(account, 1_000, NATIVE_ASSET, large.commitment()),
+            Ok((0, 0))
+        );
+        assert_eq!(manager.get_balance(account, NATIVE_ASSET), balance - 1_100);
+        assert_eq!(
+            manager.deposit_commitment(account, 10, NATIVE_ASSET, small.commitment()),
+            Err(Error::UnknownDenomination)
+        );
+        let max = Hash::from_hex("f".repeat(64)).unwrap();
+        assert_eq!(
+            manager.deposit_commitment(account, 100, NATIVE_ASSET, max),
+            Err(Error::NonCanonicalField)
+        );
+        manager.checkpoint(1);
+
+        let pool = manager.pool(small.amount()).unwrap();
+        let path = pool.find_path(0, 0).unwrap();
+        let proof = small
+            .prove(&spending_key, &path, &Payout::new(456), &proving_key, rng)
+            .unwrap();
+        let withdrawal = Withdrawal {
+            asset: NATIVE_ASSET,
+            epoch: 0,
+            root: path.construct_root(),
+            nullifier_hash: small.nullifier(),
+            payout: Payout::new(456),
+        };
+        // The root and the amount of the proof belong to the small pool.
+        assert_eq!(
+            manager.withdraw(1_000, &withdrawal, &proof),
+            Err(Error::UnknownRoot)
+        );
+        assert!(manager.withdraw(100, &withdrawal, &proof).is_ok());
+        assert!(manager
+            .pool(100)
+            .unwrap()
+            .nullifiers()
+            .contains(&small.nullifier()));
+        assert!(manager.pool(1_000).unwrap().nullifiers().is_empty());
+        assert_eq!(manager.nullifiers().len(), 1);
+
+        manager.rewind_to(1).unwrap();
+        assert!(manager.pool(100).unwrap().nullifiers().is_empty());
+        assert_eq!(manager.get_balance(account, NATIVE_ASSET), balance - 1_100);
+    }
+
+    #[test]
+    fn should_transfer_inside_a_denomination() {
+        let rng = &mut thread_rng();
+        let (_, verifying_key) = zk::setup(rng).unwrap();
+        let (transfer_proving_key, transfer_verifying_key) = zk::setup_transfer(rng).unwrap();
+        let manager = PoolManager::with_denominations(&verifying_key, &[100, 1_000]);
+        let mut manager = manager.with_transfer_key(&transfer_verifying_key);
+        let account = AnonymityPool::account();
+        let spending_key = SpendingKey::random(rng);
+
+        let note = Note::random(rng, 1_000, NATIVE_ASSET, spending_key.public_key());
+        let (epoch, index) = manager
+            .deposit_commitment(account, 1_000, NATIVE_ASSET, note.commitment())
+            .unwrap();
+        let balances = manager.balances();
+        let inputs = vec![(
+            note.clone(),
+            manager
+                .pool(1_000)
+                .unwrap()
+                .find_path(epoch, index)
+                .unwrap(),
+        )];
+        let outputs = vec![
+            Note::random(rng, 300, NATIVE_ASSET, spending_key.public_key()),
+            Note::random(rng, 700, NATIVE_ASSET, spending_key.public_key()),
+        ];
+        let proof =
+            prove_transfer(&spending_key, &inputs, &outputs, &transfer_proving_key, rng).unwrap();
+        let transfer = Transfer {
+            asset: NATIVE_ASSET,
+            epoch,
+            root: inputs[0].1.construct_root(),
+            nullifiers: vec![note.nullifier()],
+            commitments: outputs.iter().map(Note::commitment).collect(),
+        };
+
+        // The root belongs to the pool of the note.
+        assert_eq!(
+            manager.transfer(100, &transfer, &proof),
+            Err(Error::UnknownRoot)
+        );
+        assert_eq!(
+            manager.transfer(10, &transfer, &proof),
+            Err(Error::UnknownDenomination)
+        );
+        assert_eq!(
+            manager.transfer(1_000, &transfer, &proof),
+            Ok(vec![(0, 1), (0, 2)])
+        );
+        assert!(manager
+            .pool(1_000)
+            .unwrap()
+            .nullifiers()
+            .contains(&note.nullifier()));
+        assert_eq!(manager.balances(), balances);
+    }
+}
@@ -1,149 +1,1351 @@
-use crate::{hash_two, next_index, num_to_bits_vec, Error as MerkleError, Hash};
-use serde::Serialize;
-use sha3::{Digest, Keccak256};
-use std::{collections::HashMap, marker::PhantomData};
+use crate::{
+    hasher::MerkleHasher,
+    next_index, num_to_bits_vec,
+    store::{MemoryStore, NodeStore},
+    Error as MerkleError, Hash,
+};
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    marker::PhantomData,
+};
 
-#[derive(Debug, Clone, Serialize)]
-pub struct Path {
+/// Current version of the `Path::to_bytes` layout.
+pub const PATH_VERSION: u8 = 1;
+
+/// Merkle path of a single leaf value, verified with the hasher `H` of the
+/// tree that produced it.
+#[derive(Debug, Serialize)]
+#[serde(bound = "")]
+pub struct Path<H>
+where
+    H: MerkleHasher,
+{
+    index: u64,
+    neighbours: Vec<Hash>,
+    leaf: Hash,
+    #[serde(skip)]
+    _h: PhantomData<H>,
+}
+
+impl<H> Clone for Path<H>
+where
+    H: MerkleHasher,
+{
+    fn clone(&self) -> Self {
+        Self {
+            index: self.index,
+            neighbours: self.neighbours.clone(),
+            leaf: self.leaf.clone(),
+            _h: PhantomData,
+        }
+    }
+}
+
+impl<'de, H> Deserialize<'de> for Path<H>
+where
+    H: MerkleHasher,
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(rename = "Path")]
+        struct Fields {
+            index: u64,
+            neighbours: Vec<Hash>,
+            leaf: Hash,
+        }
+
+        let fields = Fields::deserialize(deserializer)?;
+        check_shape(fields.index, fields.neighbours.len()).map_err(serde::de::Error::custom)?;
+        Ok(Self::new(fields.index, fields.neighbours, fields.leaf))
+    }
+}
+
+/// A path has fewer levels than `index` has bits, and `index` fits in them.
+fn check_shape(index: u64, depth: usize) -> Result<(), MerkleError> {
+    if depth >= u64::BITS as usize || index >> depth != 0 {
+        return Err(MerkleError::InvalidPath);
+    }
+    Ok(())
+}
+
+impl<H> Path<H>
+where
+    H: MerkleHasher,
+{
+    /// Path whose `i`th neighbour is on the left when bit `i` of `index` is
+    /// set.
+    pub(crate) fn new(index: u64, neighbours: Vec<Hash>, leaf: Hash) -> Self {
+        Self {
+            index,
+            neighbours,
+            leaf,
+            _h: PhantomData,
+        }
+    }
+
+    pub fn index(&self) -> u64 {
+        self.index
+    }
+
+    pub fn neighbours(&self) -> &[Hash] {
+        &self.neighbours
+    }
+
+    pub fn leaf(&self) -> &Hash {
+        &self.leaf
+    }
+
+    pub fn construct_root(&self) -> Hash {
+        let sides = num_to_bits_vec(self.index, self.neighbours.len());
+        let mut next = H::hash_leaf(&self.leaf);
+        for (n, left) in self.neighbours.iter().zip(sides.iter()) {
+            let new_next = if *left {
+                H::hash_node(n, &next)
+            } else {
+                H::hash_node(&next, n)
+            };
+            next = new_next;
+        }
+        next
+    }
+
+    pub fn verify(&self, root: &Hash) -> Result<(), MerkleError> {
+        if self.construct_root() != *root {
+            return Err(MerkleError::InvalidPath);
+        }
+        Ok(())
+    }
+
+    /// `version || depth || index || leaf || bitmap || siblings`, integers
+    /// big-endian. Bit `i` of the bitmap (byte `i / 8`, least significant
+    /// bit first) is set when sibling `i` is the empty subtree of its level;
+    /// only the other siblings are written, in level order.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let depth = self.neighbours.len();
+        let mut bitmap = vec![0u8; depth.div_ceil(8)];
+        let mut siblings = Vec::new();
+        for (level, (n, empty)) in self
+            .neighbours
+            .iter()
+            .zip(empty_roots::<H>(depth))
+            .enumerate()
+        {
+            if *n == empty {
+                bitmap[level / 8] |= 1 << (level % 8);
+            } else {
+                siblings.extend_from_slice(n.inner());
+            }
+        }
+
+        let mut bytes = Vec::with_capacity(2 + 8 + 32 + bitmap.len() + siblings.len());
+        bytes.push(PATH_VERSION);
+        bytes.push(depth as u8);
+        bytes.extend_from_slice(&self.index.to_be_bytes());
+        bytes.extend_from_slice(self.leaf.inner());
+        bytes.extend_from_slice(&bitmap);
+        bytes.extend_from_slice(&siblings);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, MerkleError> {
+        let (header, rest) = bytes
+            .split_at_checked(2 + 8 + 32)
+            .ok_or(MerkleError::InvalidPath)?;
+        if header[0] != PATH_VERSION {
+            return Err(MerkleError::InvalidPath);
+        }
+        let depth = header[1] as usize;
+        let index = u64::from_be_bytes(header[2..10].try_into().unwrap());
+        check_shape(index, depth)?;
+        let leaf = Hash(header[10..42].try_into().unwrap());
+
+        let (bitmap, mut siblings) = rest
+            .split_at_checked(depth.div_ceil(8))
+            .ok_or(MerkleError::InvalidPath)?;
+        // Padding bits past the last level must be clear, so every path has
+        // exactly one encoding.
+        if depth % 8 != 0 && bitmap[depth / 8] >> (depth % 8) != 0 {
+            return Err(MerkleError::InvalidPath);
+        }
+        let mut neighbours = Vec::with_capacity(depth);
+        for (level, empty) in empty_roots::<H>(depth).into_iter().enumerate() {
+            if bitmap[level / 8] >> (level % 8) & 1 == 1 {
+                neighbours.push(empty);
+            } else {
+                let (sibling, rest) = siblings
+                    .split_first_chunk::<32>()
+                    .ok_or(MerkleError::InvalidPath)?;
+                neighbours.push(Hash(*sibling));
+                siblings = rest;
+            }
+        }
+        if !siblings.is_empty() {
+            return Err(MerkleError::InvalidPath);
+        }
+
+        Ok(Self {
+            index,
+            neighbours,
+            leaf,
+            _h: PhantomData,
+        })
+    }
+}
+
+/// Inclusion proof of several leaf values against one root. Siblings shared
+/// by the leaves, or that are themselves on the path of another leaf, are
+/// stored only once.
+///
+/// `siblings` are in the order the verifier consumes them: level by level
+/// from the leaves up, and by increasing node index within a level.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(bound = "")]
+pub struct MultiPath<H>
+where
+    H: MerkleHasher,
+{
+    depth: u32,
+    leaves: Vec<(u64, Hash)>,
+    siblings: Vec<Hash>,
+    #[serde(skip)]
+    _h: PhantomData<H>,
+}
+
+impl<H> Clone for MultiPath<H>
+where
+    H: MerkleHasher,
+{
+    fn clone(&self) -> Self {
+        Self {
+            depth: self.depth,
+            leaves: self.leaves.clone(),
+            siblings: self.siblings.clone(),
+            _h: PhantomData,
+        }
+    }
+}
+
+impl<H> MultiPath<H>
+where
+    H: MerkleHasher,
+{
+    /// Proven leaf values with their indices, by increasing index.
+    pub fn leaves(&self) -> &[(u64, Hash)] {
+        &self.leaves
+    }
+
+    pub fn siblings(&self) -> &[Hash] {
+        &self.siblings
+    }
+
+    pub fn construct_root(&self) -> Result<Hash, MerkleError> {
+        let mut siblings = self.siblings.iter().cloned();
+        let root = self.fold(|_, _| siblings.next().ok_or(MerkleError::InvalidPath))?;
+        if siblings.next().is_some() {
+            return Err(MerkleError::InvalidPath);
+        }
+        Ok(root)
+    }
+
+    pub fn verify(&self, root: &Hash) -> Result<(), MerkleError> {
+        if self.construct_root()? != *root {
+            return Err(MerkleError::InvalidPath);
+        }
+        Ok(())
+    }
+
+    /// Hashes the leaves up to the root, asking `sibling(level, index)` for
+    /// every node that cannot be computed from the leaves.
+    fn fold(
+        &self,
+        mut sibling: impl FnMut(u32, u64) -> Result<Hash, MerkleError>,
+    ) -> Result<Hash, MerkleError> {
+        let sorted = self.leaves.windows(2).all(|w| w[0].0 < w[1].0);
+        if self.leaves.is_empty()
+            || !sorted
+            || self.depth >= u64::BITS
+            || self
+                .leaves
+                .iter()
+                .any(|(index, _)| index >> self.depth != 0)
+        {
+            return Err(MerkleError::InvalidPath);
+        }
+
+        let mut nodes: Vec<(u64, Hash)> = self
+            .leaves
+            .iter()
+            .map(|(index, leaf)| (*index, H::hash_leaf(leaf)))
+            .collect();
+        for level in 0..self.depth {
+            let mut parents = Vec::with_capacity(nodes.len());
+            let mut known = nodes.into_iter().peekable();
+            while let Some((index, node)) = known.next() {
+                let parent = if index % 2 == 0 {
+                    let right = match known.next_if(|(next, _)| *next == index + 1) {
+                        Some((_, right)) => right,
+                        None => sibling(level, index + 1)?,
+                    };
+                    H::hash_node(&node, &right)
+                } else {
+                    H::hash_node(&sibling(level, index - 1)?, &node)
+                };
+                parents.push((index / 2, parent));
+            }
+            nodes = parents;
+        }
+        Ok(nodes.swap_remove(0).1)
+    }
+}
+
+/// Roots of the empty subtrees of levels `0..depth`.
+fn empty_roots<H: MerkleHasher>(depth: usize) -> Vec<Hash> {
+    let mut empty = H::empty_leaf();
+    let mut roots = Vec::with_capacity(depth);
+    for _ in 0..depth {
+        let next = H::hash_node(&empty, &empty);
+        roots.push(std::mem::replace(&mut empty, next));
+    }
+    roots
+}
+
+/// Proof that a tree extends an older state of itself: the first `old_size`
+/// leaves are unchanged and only appends happened since, in the spirit of
+/// Certificate Transparency (RFC 6962) consistency proofs.
+///
+/// It is the path of leaf `old_size` in the new tree. Its left siblings are
+/// the complete subtrees holding the old leaves, which must fold into the old
+/// root once every position from `old_size` on is taken as empty.
+#[derive(Debug, Serialize)]
+#[serde(bound = "")]
+pub struct ConsistencyProof<H>
+where
+    H: MerkleHasher,
+{
+    old_size: u64,
+    leaf: Hash,
+    neighbours: Vec<Hash>,
+    #[serde(skip)]
+    _h: PhantomData<H>,
+}
+
+impl<H> Clone for ConsistencyProof<H>
+where
+    H: MerkleHasher,
+{
+    fn clone(&self) -> Self {
+        Self {
+            old_size: self.old_size,
+            leaf: self.leaf.clone(),
+            neighbours: self.neighbours.clone(),
+            _h: PhantomData,
+        }
+    }
+}
+
+impl<H> ConsistencyProof<H>
+where
+    H: MerkleHasher,
+{
+    pub fn old_size(&self) -> u64 {
+        self.old_size
+    }
+
+    pub fn neighbours(&self) -> &[Hash] {
+        &self.neighbours
+    }
+
+    /// Checks that `new_root` commits to the first `old_size` leaves of
+    /// `old_root`.
+    pub fn verify(&self, old_root: &Hash, new_root: &Hash) -> Result<(), MerkleError> {
+        let depth = self.neighbours.len();
+        if depth >= u64::BITS as usize || self.old_size >> depth != 0 {
+            return Err(MerkleError::InvalidPath);
+        }
+
+        let mut zero = H::empty_leaf();
+        let mut old = zero.clone();
+        let mut new = self.leaf.clone();
+        for (level, n) in self.neighbours.iter().enumerate() {
+            if (self.old_size >> level) & 1 == 1 {
+                old = H::hash_node(n, &old);
+                new = H::hash_node(n, &new);
+            } else {
+                old = H::hash_node(&old, &zero);
+                new = H::hash_node(&new, n);
+            }
+            zero = H::hash_node(&zero, &zero);
+        }
+
+        if old != *old_root || new != *new_root {
+            return Err(MerkleError::InvalidPath);
+        }
+        Ok(())
+    }
+}
+
+/// Incremental Merkle tree keeping every node in a `NodeStore`, in memory
+/// by default.
+#[derive(Clone, Debug)]
+pub struct DenseIncrementalMerkleTree<H, const DEPTH: usize = 32, S = MemoryStore>
+where
+    H: MerkleHasher,
+    S: NodeStore,
+{
+    store: S,
+    default: HashMap<(u32, u64), Hash>,
+    index: u64,
+    checkpoints: BTreeMap<u64, u64>,
+    _h: PhantomData<H>,
+}
+
+impl<H, const DEPTH: usize> Default for DenseIncrementalMerkleTree<H, DEPTH>
+where
+    H: MerkleHasher,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<H, const DEPTH: usize> DenseIncrementalMerkleTree<H, DEPTH>
+where
+    H: MerkleHasher,
+{
+    pub fn new() -> Self {
+        Self::with_store(MemoryStore::default())
+    }
+
+    /// Tree holding `leaves`, built with `extend`.
+    pub fn from_leaves(leaves: Vec<Hash>) -> Result<Self, MerkleError> {
+        let mut tree = Self::new();
+        tree.extend(leaves)?;
+        Ok(tree)
+    }
+}
+
+impl<H, const DEPTH: usize, S> DenseIncrementalMerkleTree<H, DEPTH, S>
+where
+    H: MerkleHasher,
+    S: NodeStore,
+{
+    /// Empty tree over an empty `store`.
+    fn with_store(store: S) -> Self {
+        let () = Self::VALID_DEPTH;
+        let mut default: HashMap<(u32, u64), Hash> = HashMap::new();
+        default.insert((0, 0), H::empty_leaf());
+        for i in 0..Self::num_levels() {
+            let h = H::hash_node(&default[&(i, 0u64)], &default[&(i, 0u64)]);
+            default.insert((i + 1, 0), h);
+        }
+
+        Self {
+            store,
+            default,
+            index: 0,
+            checkpoints: BTreeMap::new(),
+            _h: PhantomData,
+        }
+    }
+
+    /// Tree over the nodes and leaves already in `store`. The stored root is
+    /// checked against one recomputed from the stored leaves, so a store left
+    /// inconsistent, e.g. by a crash in the middle of an update, is rejected
+    /// with `CorruptStore`.
+    pub fn open(store: S) -> Result<Self, MerkleError> {
+        let mut tree = Self::with_store(store);
+        let leaves = tree.store.leaves(0)?;
+        if leaves.len() as u64 != tree.store.num_leaves()? || leaves.len() as u64 > Self::capacity()
+        {
+            return Err(MerkleError::CorruptStore);
+        }
+        tree.index = leaves.len() as u64;
+
+        let recomputed = DenseIncrementalMerkleTree::<H, DEPTH>::from_leaves(leaves)?;
+        if tree.root()? != recomputed.root()? {
+            return Err(MerkleError::CorruptStore);
+        }
+        Ok(tree)
+    }
+
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    const VALID_DEPTH: () = assert!(DEPTH > 0 && DEPTH < 64, "depth must be in 1..64");
+
+    pub fn num_levels() -> u32 {
+        DEPTH as u32
+    }
+
+    /// Number of leaves the tree can hold.
+    pub fn capacity() -> u64 {
+        1 << DEPTH
+    }
+
+    pub fn root(&self) -> Result<Hash, MerkleError> {
+        self.node(Self::num_levels(), 0)
+    }
+
+    /// Number of leaves inserted so far.
+    pub fn size(&self) -> u64 {
+        self.index
+    }
+
+    /// Leaf values from index `from` onwards, in insertion order.
+    pub fn leaves(&self, from: u64) -> Result<Vec<Hash>, MerkleError> {
+        self.store.leaves(from)
+    }
+
+    /// Stored node, or the empty subtree of its level.
+    fn node(&self, level: u32, index: u64) -> Result<Hash, MerkleError> {
+        Ok(self
+            .store
+            .node(level, index)?
+            .unwrap_or_else(|| self.default[&(level, 0)].clone()))
+    }
+
+    /// Root of the tree as it was when it held `size` leaves.
+    pub fn root_at(&self, size: u64) -> Result<Hash, MerkleError> {
+        if size > self.index {
+            return Err(MerkleError::InvalidRange);
+        }
+        self.subtree_root(Self::num_levels(), 0, size)
+    }
+
+    /// Proves that the tree at `new_size` leaves extends the tree at
+    /// `old_size` leaves.
+    pub fn consistency_proof(
+        &self,
+        old_size: u64,
+        new_size: u64,
+    ) -> Result<ConsistencyProof<H>, MerkleError> {
+        if old_size >= new_size || new_size > self.index {
+            return Err(MerkleError::InvalidRange);
+        }
+        let neighbours = (0..Self::num_levels())
+            .map(|level| self.subtree_root(level, (old_size >> level) ^ 1, new_size))
+            .collect::<Result<_, _>>()?;
+
+        Ok(ConsistencyProof {
+            old_size,
+            leaf: self.subtree_root(0, old_size, new_size)?,
+            neighbours,
+            _h: PhantomData,
+        })
+    }
+
+    /// Witness of any inserted leaf, following the tree from its current
+    /// size on.
+    pub fn witness(&self, index: u64) -> Result<IncrementalWitness<H, DEPTH>, MerkleError> {
+        let path = self.find_path(index)?;
+        let size = self.index;
+        let filled_subtrees = (0..Self::num_levels())
+            .map(|level| {
+                if (size >> level) & 1 == 1 {
+                    self.subtree_root(level, (size >> level) - 1, size)
+                } else {
+                    Ok(self.default[&(level, 0)].clone())
+                }
+            })
+            .collect::<Result<_, _>>()?;
+        let zeros = (0..=Self::num_levels())
+            .map(|level| self.default[&(level, 0)].clone())
+            .collect();
+
+        let tree = FrontierMerkleTree {
+            filled_subtrees,
+            zeros,
+            root: self.root()?,
+            leaf: self
+                .store
+                .leaf(size - 1)?
+                .ok_or(MerkleError::LeafNotFound)?,
+            index: size,
+            witnesses: HashMap::new(),
+        };
+        Ok(IncrementalWitness { tree, path })
+    }
+
+    /// Node `index` at `level` of the tree restricted to its first `size`
+    /// leaves. Only one subtree per level is partially filled, so this
+    /// recurses at most once per level.
+    fn subtree_root(&self, level: u32, index: u64, size: u64) -> Result<Hash, MerkleError> {
+        let start = index << level;
+        let end = (index + 1) << level;
+        if start >= size {
+            return Ok(self.default[&(level, 0)].clone());
+        }
+        if end <= size {
+            return self.node(level, index);
+        }
+        Ok(H::hash_node(
+            &self.subtree_root(level - 1, index * 2, size)?,
+            &self.subtree_root(level - 1, index * 2 + 1, size)?,
+        ))
+    }
+
+    /// Proof for the leaves at `indices`, in any order, with repeated
+    /// indices ignored.
+    pub fn find_multi_path(&self, indices: &[u64]) -> Result<MultiPath<H>, MerkleError> {
+        let mut indices = indices.to_vec();
+        indices.sort_unstable();
+        indices.dedup();
+        if indices.is_empty() {
+            return Err(MerkleError::InvalidRange);
+        }
+        let leaves = indices
+            .into_iter()
+            .map(|index| {
+                if index >= self.index {
+                    return Err(MerkleError::LeafNotFound);
+                }
+                let leaf = self.store.leaf(index)?.ok_or(MerkleError::LeafNotFound)?;
+                Ok((index, leaf))
+            })
+            .collect::<Result<_, _>>()?;
+
+        let mut multi_path = MultiPath {
+            depth: Self::num_levels(),
+            leaves,
+            siblings: Vec::new(),
+            _h: PhantomData,
+        };
+        let mut siblings = Vec::new();
+        multi_path.fold(|level, index| {
+            let node = self.node(level, index)?;
+            siblings.push(node.clone());
+            Ok(node)
+        })?;
+        multi_path.siblings = siblings;
+        Ok(multi_path)
+    }
+
+    pub fn find_path(&self, index: u64) -> Result<Path<H>, MerkleError> {
+        if index >= self.index {
+            return Err(MerkleError::LeafNotFound);
+        }
+        let leaf = self.store.leaf(index)?.ok_or(MerkleError::LeafNotFound)?;
+        let bits = num_to_bits_vec(index, DEPTH);
+        let mut curr_index = index;
+
+        let mut neighbours = Vec::new();
+        for i in 0..Self::num_levels() {
+            let neighbour = if bits[i as usize] {
+                self.node(i, curr_index - 1)?
+            } else {
+                self.node(i, curr_index + 1)?
+            };
+            neighbours.push(neighbour);
+            curr_index = next_index(curr_index);
+        }
+
+        Ok(Path {
+            leaf,
+            neighbours,
+            index,
+            _h: PhantomData,
+        })
+    }
+
+    pub fn insert_leaf(&mut self, leaf: Hash) -> Result<u64, MerkleError> {
+        let index = self.index;
+        if index >= Self::capacity() {
+            return Err(MerkleError::TreeFull);
+        }
+        let bits = num_to_bits_vec(index, DEPTH);
+
+        let mut curr_index = index;
+        let mut curr_node = H::hash_leaf(&leaf);
+        self.store.set_node(0, index, curr_node.clone())?;
+        self.store.push_leaf(leaf)?;
+        for i in 0..Self::num_levels() {
+            let (left, right) = if bits[i as usize] {
+                (self.node(i, curr_index - 1)?, curr_node)
+            } else {
+                (curr_node, self.node(i, curr_index + 1)?)
+            };
+
+            let h = H::hash_node(&left, &right);
+            curr_node = h;
+            curr_index = next_index(curr_index);
+
+            self.store.set_node(i + 1, curr_index, curr_node.clone())?;
+        }
+        self.store.flush()?;
+
+        self.index += 1;
+        Ok(index)
+    }
+
+    /// Records the current size under `id`, e.g. a block height. Ids are
+    /// expected to increase; an existing id is overwritten. Checkpoints are
+    /// not persisted by the store.
+    pub fn checkpoint(&mut self, id: u64) {
+        self.checkpoints.insert(id, self.index);
+    }
+
+    /// Drops every leaf inserted after checkpoint `id`, and every later
+    /// checkpoint. The checkpoint itself is kept, so it can be rewound to
+    /// again.
+    pub fn rewind_to(&mut self, id: u64) -> Result<(), MerkleError> {
+        let size = *self
+            .checkpoints
+            .get(&id)
+            .ok_or(MerkleError::CheckpointNotFound)?;
+        self.checkpoints.split_off(&(id + 1));
+        self.truncate(size)
+    }
+
+    /// Removes the leaves from index `size` on. Nodes covering only removed
+    /// leaves are dropped, and the one node per level that straddles `size`
+    /// is hashed again.
+    fn truncate(&mut self, size: u64) -> Result<(), MerkleError> {
+        let old_size = self.index;
+        if size >= old_size {
+            return Ok(());
+        }
+
+        for level in 0..=Self::num_levels() {
+            let first = (size + (1 << level) - 1) >> level;
+            let last = (old_size - 1) >> level;
+            for index in first..=last {
+                self.store.remove_node(level, index)?;
+            }
+        }
+        for level in 1..=Self::num_levels() {
+            let index = size >> level;
+            if index << level == size {
+                continue;
+            }
+            let left = self.node(level - 1, index * 2)?;
+            let right = self.node(level - 1, index * 2 + 1)?;
+            self.store
+                .set_node(level, index, H::hash_node(&left, &right))?;
+        }
+        self.store.truncate_leaves(size)?;
+        self.store.flush()?;
+
+        self.index = size;
+        Ok(())
+    }
+
+    /// Appends `leaves` one level at a time, bottom-up, hashing the nodes of
+    /// each level in parallel with the `parallel` feature. Ends in the same state as inserting the
+    /// leaves one by one with `insert_leaf`.
+    pub fn extend(&mut self, leaves: Vec<Hash>) -> Result<(), MerkleError> {
+        if leaves.is_empty() {
+            return Ok(());
+        }
+        let start = self.index;
+        if leaves.len() as u64 > Self::capacity() - start {
+            return Err(MerkleError::TreeFull);
+        }
+        let end = start + leaves.len() as u64;
+
+        #[cfg(feature = "parallel")]
+        let leaf_nodes = leaves.par_iter();
+        #[cfg(not(feature = "parallel"))]
+        let leaf_nodes = leaves.iter();
+        let leaf_nodes: Vec<Hash> = leaf_nodes.map(H::hash_leaf).collect();
+        for (i, node) in (start..end).zip(leaf_nodes) {
+            self.store.set_node(0, i, node)?;
+        }
+        for leaf in leaves {
+            self.store.push_leaf(leaf)?;
+        }
+
+        let (mut first, mut last) = (start, end - 1);
+        for level in 0..Self::num_levels() {
+            first /= 2;
+            last /= 2;
+            let store = &self.store;
+            let default = &self.default[&(level, 0)];
+            let node = |index| -> Result<Hash, MerkleError> {
+                Ok(store.node(level, index)?.unwrap_or_else(|| default.clone()))
+            };
+            #[cfg(feature = "parallel")]
+            let parents = (first..=last).into_par_iter();
+            #[cfg(not(feature = "parallel"))]
+            let parents = first..=last;
+            let parents: Vec<(u64, Hash)> = parents
+                .map(|parent| {
+                    Ok((
+                        parent,
+                        H::hash_node(&node(parent * 2)?, &node(parent * 2 + 1)?),
+                    ))
+                })
+                .collect::<Result<_, MerkleError>>()?;
+            for (parent, node) in parents {
+                self.store.set_node(level + 1, parent, node)?;
+            }
+        }
+        self.store.flush()?;
+
+        self.index = end;
+        Ok(())
+    }
+
+    #[cfg(test)]
+    pub fn insert_batch(&mut self, leaves: Vec<Hash>) -> Result<(), MerkleError> {
+        for leaf in leaves {
+            self.insert_leaf(leaf)?;
+        }
+        Ok(())
+    }
+}
+
+/// Incremental Merkle tree that stores only the rightmost "filled subtree" of
+/// every level, in the style of Tornado Cash's `MerkleTreeWithHistory`.
+///
+/// Memory is O(depth) regardless of the number of leaves. Paths are kept only
+/// for leaves inserted with `track_leaf`, and for the most recent leaf.
+#[derive(Clone, Debug)]
+pub struct FrontierMerkleTree<H, const DEPTH: usize = 32>
+where
+    H: MerkleHasher,
+{
+    filled_subtrees: Vec<Hash>,
+    zeros: Vec<Hash>,
+    root: Hash,
+    leaf: Hash,
     index: u64,
+    witnesses: HashMap<u64, Path<H>>,
+}
+
+impl<H, const DEPTH: usize> Default for FrontierMerkleTree<H, DEPTH>
+where
+    H: MerkleHasher,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<H, const DEPTH: usize> FrontierMerkleTree<H, DEPTH>
+where
+    H: MerkleHasher,
+{
+    pub fn new() -> Self {
+        let () = Self::VALID_DEPTH;
+        let mut zeros = vec![H::empty_leaf()];
+        for i in 0..Self::num_levels() as usize {
+            zeros.push(H::hash_node(&zeros[i], &zeros[i]));
+        }
+
+        Self {
+            filled_subtrees: zeros[..Self::num_levels() as usize].to_vec(),
+            root: zeros[Self::num_levels() as usize].clone(),
+            zeros,
+            leaf: Hash::default(),
+            index: 0,
+            witnesses: HashMap::new(),
+        }
+    }
+
+    const VALID_DEPTH: () = assert!(DEPTH > 0 && DEPTH < 64, "depth must be in 1..64");
+
+    pub fn num_levels() -> u32 {
+        DEPTH as u32
+    }
+
+    /// Number of leaves the tree can hold.
+    pub fn capacity() -> u64 {
+        1 << DEPTH
+    }
+
+    pub fn root(&self) -> Result<Hash, MerkleError> {
+        Ok(self.root.clone())
+    }
+
+    /// Number of leaves inserted so far.
+    pub fn size(&self) -> u64 {
+        self.index
+    }
+
+    /// Path of a tracked leaf, or of the most recently inserted one.
+    pub fn find_path(&self, index: u64) -> Result<Path<H>, MerkleError> {
+        if let Some(path) = self.witnesses.get(&index) {
+            return Ok(path.clone());
+        }
+        if self.index == 0 || index != self.index - 1 {
+            return Err(MerkleError::LeafNotFound);
+        }
+        Ok(self.frontier_path(index, self.leaf.clone()))
+    }
+
+    pub fn insert_leaf(&mut self, leaf: Hash) -> Result<u64, MerkleError> {
+        let (index, path_nodes) = self.append(leaf)?;
+        for path in self.witnesses.values_mut() {
+            update_path(path, index, &path_nodes);
+        }
+        Ok(index)
+    }
+
+    /// Appends a leaf to the frontier and returns its index together with
+    /// the nodes on its path, from the leaf up to just below the root.
+    fn append(&mut self, leaf: Hash) -> Result<(u64, Vec<Hash>), MerkleError> {
+        let index = self.index;
+        if index >= Self::capacity() {
+            return Err(MerkleError::TreeFull);
+        }
+
+        let mut curr_index = index;
+        let mut curr_node = H::hash_leaf(&leaf);
+        let mut path_nodes = Vec::with_capacity(Self::num_levels() as usize);
+        for i in 0..Self::num_levels() as usize {
+            path_nodes.push(curr_node.clone());
+            let (left, right) = if curr_index % 2 == 0 {
+                self.filled_subtrees[i] = curr_node.clone();
+                (curr_node, self.zeros[i].clone())
+            } else {
+                (self.filled_subtrees[i].clone(), curr_node)
+            };
+            curr_node = H::hash_node(&left, &right);
+            curr_index = next_index(curr_index);
+        }
+        self.root = curr_node;
+
+        self.leaf = leaf;
+        self.index += 1;
+        Ok((index, path_nodes))
+    }
+
+    /// Inserts a leaf and keeps its path up to date on later insertions.
+    pub fn track_leaf(&mut self, leaf: Hash) -> Result<u64, MerkleError> {
+        let index = self.insert_leaf(leaf.clone())?;
+        let path = self.frontier_path(index, leaf);
+        self.witnesses.insert(index, path);
+        Ok(index)
+    }
+
+    pub fn untrack_leaf(&mut self, index: u64) -> Option<Path<H>> {
+        self.witnesses.remove(&index)
+    }
+
+    /// Proves that the tree extended with `appended` keeps the leaves of
+    /// this one. The left siblings are the filled subtrees of the frontier,
+    /// so only the appended leaves are hashed.
+    pub fn consistency_proof(&self, appended: &[Hash]) -> Result<ConsistencyProof<H>, MerkleError> {
+        let old_size = self.index;
+        if appended.is_empty() || appended.len() as u64 > Self::capacity() - old_size {
+            return Err(MerkleError::InvalidRange);
+        }
+        let neighbours = (0..Self::num_levels() as usize)
+            .map(|level| {
+                let index = old_size >> level;
+                if index & 1 == 1 {
+                    return self.filled_subtrees[level].clone();
+                }
+                // Leaves of the right sibling, counted from `old_size`.
+                let start = (((index + 1) << level) - old_size) as usize;
+                let end = start.saturating_add(1 << level).min(appended.len());
+                self.subtree_root(level, appended.get(start..end).unwrap_or_default())
+            })
+            .collect();
+
+        Ok(ConsistencyProof {
+            old_size,
+            leaf: H::hash_leaf(&appended[0]),
+            neighbours,
+            _h: PhantomData,
+        })
+    }
+
+    /// Root of a subtree of height `level` holding `leaves` on its left and
+    /// empty leaves after them.
+    fn subtree_root(&self, level: usize, leaves: &[Hash]) -> Hash {
+        let mut nodes: Vec<Hash> = leaves.iter().map(H::hash_leaf).collect();
+        for zero in &self.zeros[..level] {
+            if nodes.is_empty() {
+                break;
+            }
+            nodes = nodes
+                .chunks(2)
+                .map(|pair| H::hash_node(&pair[0], pair.get(1).unwrap_or(zero)))
+                .collect();
+        }
+        nodes.pop().unwrap_or_else(|| self.zeros[level].clone())
+    }
+
+    /// Path of the last inserted leaf: left siblings are the filled
+    /// subtrees, right siblings are still empty.
+    fn frontier_path(&self, index: u64, leaf: Hash) -> Path<H> {
+        let bits = num_to_bits_vec(index, DEPTH);
+        let neighbours = (0..Self::num_levels() as usize)
+            .map(|i| {
+                if bits[i] {
+                    self.filled_subtrees[i].clone()
+                } else {
+                    self.zeros[i].clone()
+                }
+            })
+            .collect();
+
+        Path {
+            leaf,
+            neighbours,
+            index,
+            _h: PhantomData,
+        }
+    }
+}
+
+/// Each earlier path changes in exactly one sibling when a leaf is appended:
+/// at the level where the new leaf's subtree becomes its neighbour.
+fn update_path<H: MerkleHasher>(path: &mut Path<H>, index: u64, path_nodes: &[Hash]) {
+    let level = (u64::BITS - (path.index ^ index).leading_zeros() - 1) as usize;
+    path.neighbours[level] = path_nodes[level].clone();
+}
+
+/// Authentication path of one leaf that follows later appends to the tree,
+/// in the style of Zcash's `IncrementalWitness`.
+///
+/// Holds only the tree frontier and the path, so a wallet can keep its notes
+/// spendable against the latest root by feeding it every new commitment.
+#[derive(Clone, Debug)]
+pub struct IncrementalWitness<H, const DEPTH: usize = 32>
+where
+    H: MerkleHasher,
+{
+    tree: FrontierMerkleTree<H, DEPTH>,
+    path: Path<H>,
+}
+
+impl<H, const DEPTH: usize> IncrementalWitness<H, DEPTH>
+where
+    H: MerkleHasher,
+{
+    /// Witness for the most recently inserted leaf of `tree`.
+    pub fn from_tree(tree: &FrontierMerkleTree<H, DEPTH>) -> Result<Self, MerkleError> {
+        if tree.index == 0 {
+            return Err(MerkleError::LeafNotFound);
+        }
+        let index = tree.index - 1;
+        let tree = FrontierMerkleTree {
+            filled_subtrees: tree.filled_subtrees.clone(),
+            zeros: tree.zeros.clone(),
+            root: tree.root.clone(),
+            leaf: tree.leaf.clone(),
+            index: tree.index,
+            witnesses: HashMap::new(),
+        };
+        let path = tree.frontier_path(index, tree.leaf.clone());
+        Ok(Self { tree, path })
+    }
+
+    /// Index of the witnessed leaf.
+    pub fn position(&self) -> u64 {
+        self.path.index
+    }
+
+    pub fn leaf(&self) -> &Hash {
+        &self.path.leaf
+    }
+
+    /// Number of tree leaves the witness has seen.
+    pub fn size(&self) -> u64 {
+        self.tree.size()
+    }
+
+    pub fn root(&self) -> Hash {
+        self.tree.root.clone()
+    }
+
+    /// Path of the witnessed leaf against `root()`.
+    pub fn path(&self) -> Path<H> {
+        self.path.clone()
+    }
+
+    /// Appends the next leaf of the tree and updates the path.
+    pub fn append(&mut self, leaf: Hash) -> Result<u64, MerkleError> {
+        let (index, path_nodes) = self.tree.append(leaf)?;
+        update_path(&mut self.path, index, &path_nodes);
+        Ok(index)
+    }
+}
+
+/// Depth of a `SparseMerkleTree`: one level per bit of the key.
+pub const SPARSE_DEPTH: u32 = 256;
+
+/// Path of a key in a `SparseMerkleTree`. Proves either that the key is in
+/// the set or, with an empty leaf, that it is not.
+#[derive(Debug, Serialize)]
+#[serde(bound = "")]
+pub struct SparsePath<H>
+where
+    H: MerkleHasher,
+{
+    key: Hash,
     neighbours: Vec<Hash>,
+    /// Leaf node of the key: `hash_leaf(key)` if present, empty otherwise.
     leaf: Hash,
+    #[serde(skip)]
+    _h: PhantomData<H>,
+}
+
+impl<H> Clone for SparsePath<H>
+where
+    H: MerkleHasher,
+{
+    fn clone(&self) -> Self {
+        Self {
+            key: self.key.clone(),
+            neighbours: self.neighbours.clone(),
+            leaf: self.leaf.clone(),
+            _h: PhantomData,
+        }
+    }
 }
 
-impl Path {
+impl<H> SparsePath<H>
+where
+    H: MerkleHasher,
+{
+    pub fn key(&self) -> &Hash {
+        &self.key
+    }
+
+    pub fn neighbours(&self) -> &[Hash] {
+        &self.neighbours
+    }
+
+    pub fn leaf(&self) -> &Hash {
+        &self.leaf
+    }
+
+    /// Whether the path claims the key is in the set.
+    pub fn is_member(&self) -> bool {
+        self.leaf != H::empty_leaf()
+    }
+
     pub fn construct_root(&self) -> Hash {
-        let sides = num_to_bits_vec(self.index);
         let mut next = self.leaf.clone();
-        for (n, left) in self
-            .neighbours
-            .iter()
-            .zip(sides[..self.neighbours.len()].as_ref())
-        {
-            let new_next = if *left {
-                hash_two::<Keccak256>(n.clone(), next)
+        for (level, n) in self.neighbours.iter().enumerate() {
+            next = if key_bit(&self.key, level as u32) {
+                H::hash_node(n, &next)
             } else {
-                hash_two::<Keccak256>(next, n.clone())
+                H::hash_node(&next, n)
             };
-            next = new_next;
         }
         next
     }
+
+    /// Checks that the key is in the set committed to by `root`.
+    pub fn verify_membership(&self, root: &Hash) -> Result<(), MerkleError> {
+        if self.leaf != H::hash_leaf(&self.key) {
+            return Err(MerkleError::InvalidPath);
+        }
+        self.verify(root)
+    }
+
+    /// Checks that the key is absent from the set committed to by `root`.
+    pub fn verify_non_membership(&self, root: &Hash) -> Result<(), MerkleError> {
+        if self.is_member() {
+            return Err(MerkleError::InvalidPath);
+        }
+        self.verify(root)
+    }
+
+    fn verify(&self, root: &Hash) -> Result<(), MerkleError> {
+        if self.neighbours.len() != SPARSE_DEPTH as usize || self.construct_root() != *root {
+            return Err(MerkleError::InvalidPath);
+        }
+        Ok(())
+    }
 }
 
+/// Set of 256-bit keys committed to by a Merkle tree with one leaf per
+/// possible key. Absent keys have an empty leaf, so only the nodes above
+/// inserted keys are stored.
 #[derive(Clone, Debug)]
-pub struct DenseIncrementalMerkleTree<H>
+pub struct SparseMerkleTree<H>
 where
-    H: Digest,
+    H: MerkleHasher,
 {
-    nodes: HashMap<(u32, u64), Hash>,
-    default: HashMap<(u32, u64), Hash>,
-    index: u64,
+    nodes: HashMap<(u32, Hash), Hash>,
+    default: Vec<Hash>,
     _h: PhantomData<H>,
 }
 
-impl<H> DenseIncrementalMerkleTree<H>
+impl<H> Default for SparseMerkleTree<H>
+where
+    H: MerkleHasher,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<H> SparseMerkleTree<H>
 where
-    H: Digest,
+    H: MerkleHasher,
 {
     pub fn new() -> Self {
-        let mut default: HashMap<(u32, u64), Hash> = HashMap::new();
-        default.insert((0, 0), Hash::default());
-        for i in 0..Self::num_levels() {
-            let h = hash_two::<H>(default[&(i, 0u64)].clone(), default[&(i, 0u64)].clone());
-            default.insert((i + 1, 0), h);
+        let mut default = vec![H::empty_leaf()];
+        for i in 0..SPARSE_DEPTH as usize {
+            default.push(H::hash_node(&default[i], &default[i]));
         }
 
         Self {
-            nodes: default.clone(),
+            nodes: HashMap::new(),
             default,
-            index: 0,
             _h: PhantomData,
         }
     }
 
-    pub fn num_levels() -> u32 {
-        u32::BITS
+    pub fn root(&self) -> Hash {
+        self.node(SPARSE_DEPTH, &Hash::default())
     }
 
-    pub fn root(&self) -> Result<Hash, MerkleError> {
+    pub fn contains(&self, key: &Hash) -> bool {
+        self.nodes.contains_key(&(0, key.clone()))
+    }
+
+    /// Keys in the set, in no particular order.
+    pub fn keys(&self) -> impl Iterator<Item = &Hash> {
         self.nodes
-            .get(&(Self::num_levels(), 0))
-            .cloned()
-            .ok_or(MerkleError::RootNotFound)
+            .keys()
+            .filter(|(level, _)| *level == 0)
+            .map(|(_, key)| key)
     }
 
-    pub fn find_path(&self, index: u64) -> Path {
-        let bits = num_to_bits_vec(index);
-        let mut curr_index = index;
+    /// Adds `key` to the set. Returns whether it was absent.
+    pub fn insert(&mut self, key: Hash) -> bool {
+        if self.contains(&key) {
+            return false;
+        }
 
-        let mut neighbours = Vec::new();
-        for i in 0..Self::num_levels() {
-            let neighbour = if bits[i as usize] {
-                let n_key = (i, curr_index - 1);
-                let n = self.nodes.get(&n_key).unwrap_or(&self.default[&(i, 0)]);
-                n
+        let mut curr_node = H::hash_leaf(&key);
+        self.nodes.insert((0, key.clone()), curr_node.clone());
+        for level in 0..SPARSE_DEPTH {
+            let sibling = self.node(level, &sibling_prefix(&key, level));
+            curr_node = if key_bit(&key, level) {
+                H::hash_node(&sibling, &curr_node)
             } else {
-                let n_key = (i, curr_index + 1);
-                let n = self.nodes.get(&n_key).unwrap_or(&self.default[&(i, 0)]);
-                n
+                H::hash_node(&curr_node, &sibling)
             };
-            neighbours.push(neighbour.clone());
-            curr_index = next_index(curr_index);
-        }
-
-        let leaf = self.nodes.get(&(0, index)).cloned().unwrap();
-
-        Path {
-            leaf,
-            neighbours,
-            index,
+            self.nodes
+                .insert((level + 1, key_prefix(&key, level + 1)), curr_node.clone());
         }
+        true
     }
 
-    pub fn insert_leaf(&mut self, leaf: Hash) -> u64 {
-        let max_size = 2u64.pow(Self::num_levels()) - 1;
-        let index = self.index;
-        assert!(index + 1 < max_size);
-        let bits = num_to_bits_vec(index);
-
-        self.nodes.insert((0, index), leaf.clone());
+    /// Removes `key` from the set, pruning the nodes that become empty.
+    /// Returns whether it was present.
+    pub fn remove(&mut self, key: &Hash) -> bool {
+        if self.nodes.remove(&(0, key.clone())).is_none() {
+            return false;
+        }
 
-        let mut curr_index = index;
-        let mut curr_node = leaf;
-        for i in 0..Self::num_levels() {
-            let (left, right) = if bits[i as usize] {
-                let n_key = (i, curr_index - 1);
-                let n = self.nodes.get(&n_key).unwrap_or(&self.default[&(i, 0)]);
-                (n.clone(), curr_node)
+        for level in 0..SPARSE_DEPTH {
+            let node = self.node(level, &key_prefix(key, level));
+            let sibling = self.node(level, &sibling_prefix(key, level));
+            let parent = if key_bit(key, level) {
+                H::hash_node(&sibling, &node)
             } else {
-                let n_key = (i, curr_index + 1);
-                let n = self.nodes.get(&n_key).unwrap_or(&self.default[&(i, 0)]);
-                (curr_node, n.clone())
+                H::hash_node(&node, &sibling)
             };
+            let parent_key = (level + 1, key_prefix(key, level + 1));
+            if parent == self.default[level as usize + 1] {
+                self.nodes.remove(&parent_key);
+            } else {
+                self.nodes.insert(parent_key, parent);
+            }
+        }
+        true
+    }
 
-            let h = hash_two::<H>(left, right);
-            curr_node = h;
-            curr_index = next_index(curr_index);
+    /// Membership proof if `key` is in the set, non-membership proof
+    /// otherwise.
+    pub fn find_path(&self, key: &Hash) -> SparsePath<H> {
+        let neighbours = (0..SPARSE_DEPTH)
+            .map(|level| self.node(level, &sibling_prefix(key, level)))
+            .collect();
 
-            self.nodes.insert((i + 1, curr_index), curr_node.clone());
+        SparsePath {
+            key: key.clone(),
+            neighbours,
+            leaf: self.node(0, key),
+            _h: PhantomData,
         }
+    }
 
-        self.index += 1;
-        index
+    fn node(&self, level: u32, prefix: &Hash) -> Hash {
+        self.nodes
+            .get(&(level, prefix.clone()))
+            .unwrap_or(&self.default[level as usize])
+            .clone()
     }
+}
 
-    #[cfg(test)]
-    pub fn insert_batch(&mut self, leaves: Vec<Hash>) {
-        for leaf in leaves {
-            self.insert_leaf(leaf);
-        }
+/// Bit `level` of the key, counted from the least significant one, selects
+/// the side of the node at that level.
+fn key_bit(key: &Hash, level: u32) -> bool {
+    let byte = key.0[31 - (level / 8) as usize];
+    (byte >> (level % 8)) & 1 == 1
+}
+
+/// Key of the node at `level` above `key`: its low `level` bits cleared.
+fn key_prefix(key: &Hash, level: u32) -> Hash {
+    let mut prefix = key.clone();
+    for i in 0..level.min(SPARSE_DEPTH) {
+        prefix.0[31 - (i / 8) as usize] &= !(1 << (i % 8));
     }
+    prefix
+}
+
+/// Key of the sibling of the node at `level` above `key`.
+fn sibling_prefix(key: &Hash, level: u32) -> Hash {
+    let mut prefix = key_prefix(key, level);
+    prefix.0[31 - (level / 8) as usize] ^= 1 << (level % 8);
+    prefix
 }
 
 #[cfg(test)]
 mod test {
-    use super::{DenseIncrementalMerkleTree, Hash};
-    use sha3::Keccak256;
+    use super::{
+        DenseIncrementalMerkleTree, FrontierMerkleTree, Hash, IncrementalWitness, Path,
+        SparseMerkleTree,
+    };
+    use crate::hasher::{Legacy, MerkleHasher, Tagged};
+    use crate::poseidon::Poseidon;
+    use crate::Error;
+    use blake2::Blake2s256;
+    use rand::thread_rng;
+    use sha2::Sha256;
+    use sha3::{Keccak256, Sha3_256};
+    use std::marker::PhantomData;
+
+    fn should_verify_paths<H: MerkleHasher>() {
+        let rng = &mut thread_rng();
+        let mut merkle = DenseIncrementalMerkleTree::<H>::new();
+        for _ in 0..10 {
+            merkle.insert_leaf(Hash::random(rng)).unwrap();
+        }
+        let root = merkle.root().unwrap();
+
+        for index in 0..10 {
+            let path = merkle.find_path(index).unwrap();
+            assert_eq!(path.construct_root(), root);
+            assert!(path.verify(&root).is_ok());
+            assert!(path.verify(&Hash::random(rng)).is_err());
+        }
+    }
+
+    #[test]
+    fn should_verify_paths_for_any_digest() {
+        should_verify_paths::<Tagged<Keccak256>>();
+        should_verify_paths::<Tagged<Sha256>>();
+        should_verify_paths::<Tagged<Sha3_256>>();
+        should_verify_paths::<Tagged<Blake2s256>>();
+        should_verify_paths::<Tagged<Poseidon>>();
+        should_verify_paths::<Legacy<Keccak256>>();
+        should_verify_paths::<Legacy<Poseidon>>();
+    }
 
     #[test]
     fn should_build_incremental_tree() {
@@ -170,8 +1372,9 @@ mod test {
             Hash::default(),
             Hash::default(),
         ];
-        let mut merkle = DenseIncrementalMerkleTree::<Keccak256>::new();
-        merkle.insert_batch(leaves);
+        // Compatibility mode reproduces trees built before domain separation.
+        let mut merkle = DenseIncrementalMerkleTree::<Legacy<Keccak256>>::new();
+        merkle.insert_batch(leaves).unwrap();
         let root = merkle.root().unwrap();
 
         assert_eq!(
@@ -179,4 +1382,522 @@ mod test {
             "27ae5ba08d7291c96c8cbddcc148bf48a6d68c7974b94356f53754ef6171d757".to_string()
         );
     }
+
+    #[test]
+    fn should_reject_inner_node_as_leaf() {
+        let rng = &mut thread_rng();
+        fn forged_path<H: MerkleHasher>(leaves: &[Hash]) -> (Hash, Path<H>) {
+            let mut merkle = DenseIncrementalMerkleTree::<H, 2>::new();
+            for leaf in leaves {
+                merkle.insert_leaf(leaf.clone()).unwrap();
+            }
+            let left = H::hash_node(&H::hash_leaf(&leaves[0]), &H::hash_leaf(&leaves[1]));
+            let right = H::hash_node(&H::hash_leaf(&leaves[2]), &H::hash_leaf(&leaves[3]));
+            let path = Path {
+                index: 0,
+                neighbours: vec![right],
+                leaf: left,
+                _h: PhantomData,
+            };
+            (merkle.root().unwrap(), path)
+        }
+        let leaves: Vec<Hash> = (0..4).map(|_| Hash::random(rng)).collect();
+
+        let (root, path) = forged_path::<Legacy<Keccak256>>(&leaves);
+        assert!(path.verify(&root).is_ok());
+        let (root, path) = forged_path::<Tagged<Keccak256>>(&leaves);
+        assert_eq!(path.verify(&root), Err(Error::InvalidPath));
+    }
+
+    #[test]
+    fn should_follow_tree_depth() {
+        let rng = &mut thread_rng();
+        let mut dense = DenseIncrementalMerkleTree::<Tagged<Keccak256>, 3>::new();
+        let mut frontier = FrontierMerkleTree::<Tagged<Keccak256>, 3>::new();
+        assert_eq!(
+            DenseIncrementalMerkleTree::<Tagged<Keccak256>, 3>::capacity(),
+            8
+        );
+
+        for i in 0..8 {
+            let leaf = Hash::random(rng);
+            dense.insert_leaf(leaf.clone()).unwrap();
+            frontier.insert_leaf(leaf).unwrap();
+            let path = dense.find_path(i).unwrap();
+            assert_eq!(path.neighbours().len(), 3);
+            assert!(path.verify(&dense.root().unwrap()).is_ok());
+            assert_eq!(frontier.root().unwrap(), dense.root().unwrap());
+        }
+    }
+
+    #[test]
+    fn should_reject_leaves_beyond_capacity() {
+        let mut dense = DenseIncrementalMerkleTree::<Tagged<Keccak256>, 2>::new();
+        let mut frontier = FrontierMerkleTree::<Tagged<Keccak256>, 2>::new();
+        for _ in 0..4 {
+            dense.insert_leaf(Hash::default()).unwrap();
+            frontier.insert_leaf(Hash::default()).unwrap();
+        }
+        assert_eq!(dense.insert_leaf(Hash::default()), Err(Error::TreeFull));
+        assert_eq!(frontier.insert_leaf(Hash::default()), Err(Error::TreeFull));
+        assert_eq!(dense.find_path(4).unwrap_err(), Error::LeafNotFound);
+    }
+
+    #[test]
+    fn frontier_tree_should_match_dense_tree() {
+        let rng = &mut thread_rng();
+        let mut dense = DenseIncrementalMerkleTree::<Tagged<Keccak256>>::new();
+        let mut frontier = FrontierMerkleTree::<Tagged<Keccak256>>::new();
+        assert_eq!(frontier.root().unwrap(), dense.root().unwrap());
+
+        let mut tracked = Vec::new();
+        for i in 0..37 {
+            let leaf = Hash::random(rng);
+            dense.insert_leaf(leaf.clone()).unwrap();
+            if i % 5 == 0 {
+                tracked.push(frontier.track_leaf(leaf).unwrap());
+            } else {
+                frontier.insert_leaf(leaf).unwrap();
+            }
+            assert_eq!(frontier.root().unwrap(), dense.root().unwrap());
+            let latest = frontier.find_path(i).unwrap();
+            assert_eq!(latest.construct_root(), dense.root().unwrap());
+        }
+
+        let root = dense.root().unwrap();
+        for index in tracked {
+            let path = frontier.find_path(index).unwrap();
+            assert!(path.verify(&root).is_ok());
+            assert_eq!(
+                path.neighbours(),
+                dense.find_path(index).unwrap().neighbours()
+            );
+        }
+        assert!(frontier.find_path(1).is_err());
+    }
+
+    #[test]
+    fn incremental_witness_should_follow_appends() {
+        let rng = &mut thread_rng();
+        let mut dense = DenseIncrementalMerkleTree::<Tagged<Keccak256>, 6>::new();
+        let mut frontier = FrontierMerkleTree::<Tagged<Keccak256>, 6>::new();
+        assert_eq!(
+            IncrementalWitness::from_tree(&frontier).unwrap_err(),
+            Error::LeafNotFound
+        );
+
+        let mut witnesses: Vec<IncrementalWitness<Tagged<Keccak256>, 6>> = Vec::new();
+        for i in 0..40 {
+            let leaf = Hash::random(rng);
+            dense.insert_leaf(leaf.clone()).unwrap();
+            frontier.insert_leaf(leaf.clone()).unwrap();
+            for witness in witnesses.iter_mut() {
+                assert_eq!(witness.append(leaf.clone()).unwrap(), i);
+            }
+            if i % 7 == 3 {
+                witnesses.push(IncrementalWitness::from_tree(&frontier).unwrap());
+            }
+
+            let root = dense.root().unwrap();
+            for witness in &witnesses {
+                assert_eq!(witness.root(), root);
+                assert_eq!(witness.size(), i + 1);
+                let path = witness.path();
+                assert!(path.verify(&root).is_ok());
+                assert_eq!(
+                    path.neighbours(),
+                    dense.find_path(witness.position()).unwrap().neighbours()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn sparse_tree_should_prove_membership_and_non_membership() {
+        let rng = &mut thread_rng();
+        let mut set = SparseMerkleTree::<Tagged<Keccak256>>::new();
+        let empty_root = set.root();
+        let keys: Vec<Hash> = (0..8).map(|_| Hash::random(rng)).collect();
+        for key in &keys {
+            assert!(set.insert(key.clone()));
+        }
+        assert!(!set.insert(keys[0].clone()));
+        assert_eq!(set.keys().count(), keys.len());
+
+        let root = set.root();
+        assert_ne!(root, empty_root);
+        for key in &keys {
+            let path = set.find_path(key);
+            assert!(path.is_member());
+            assert!(path.verify_membership(&root).is_ok());
+            assert_eq!(path.verify_non_membership(&root), Err(Error::InvalidPath));
+            assert_eq!(path.verify_membership(&empty_root), Err(Error::InvalidPath));
+        }
+
+        let absent = Hash::random(rng);
+        let path = set.find_path(&absent);
+        assert!(!path.is_member());
+        assert!(path.verify_non_membership(&root).is_ok());
+        assert_eq!(path.verify_membership(&root), Err(Error::InvalidPath));
+
+        // Neighbouring keys share all but the lowest level.
+        let mut near = keys[0].clone();
+        near.0[31] ^= 1;
+        assert!(set.find_path(&near).verify_non_membership(&root).is_ok());
+        set.insert(near.clone());
+        assert!(set.find_path(&near).verify_membership(&set.root()).is_ok());
+        assert!(set
+            .find_path(&keys[0])
+            .verify_membership(&set.root())
+            .is_ok());
+    }
+
+    #[test]
+    fn sparse_root_should_not_depend_on_insertion_order() {
+        let rng = &mut thread_rng();
+        let keys: Vec<Hash> = (0..6).map(|_| Hash::random(rng)).collect();
+        let mut forward = SparseMerkleTree::<Tagged<Keccak256>>::new();
+        let mut backward = SparseMerkleTree::<Tagged<Keccak256>>::new();
+        for key in &keys {
+            forward.insert(key.clone());
+        }
+        for key in keys.iter().rev() {
+            backward.insert(key.clone());
+        }
+        assert_eq!(forward.root(), backward.root());
+    }
+
+    #[test]
+    fn should_prove_consistency_between_sizes() {
+        let rng = &mut thread_rng();
+        let mut merkle = DenseIncrementalMerkleTree::<Tagged<Keccak256>, 4>::new();
+        let mut frontier = FrontierMerkleTree::<Tagged<Keccak256>, 4>::new();
+        let mut roots = vec![merkle.root().unwrap()];
+        let mut frontiers = vec![frontier.clone()];
+        let mut leaves = Vec::new();
+        for _ in 0..13 {
+            let leaf = Hash::random(rng);
+            merkle.insert_leaf(leaf.clone()).unwrap();
+            frontier.insert_leaf(leaf.clone()).unwrap();
+            roots.push(merkle.root().unwrap());
+            frontiers.push(frontier.clone());
+            leaves.push(leaf);
+        }
+
+        for new_size in 0..roots.len() as u64 {
+            assert_eq!(merkle.root_at(new_size).unwrap(), roots[new_size as usize]);
+            for old_size in 0..new_size {
+                let proof = merkle.consistency_proof(old_size, new_size).unwrap();
+                let (old_root, new_root) = (&roots[old_size as usize], &roots[new_size as usize]);
+                assert!(proof.verify(old_root, new_root).is_ok());
+                assert_eq!(proof.verify(new_root, old_root), Err(Error::InvalidPath));
+                assert_eq!(
+                    proof.verify(old_root, &Hash::random(rng)),
+                    Err(Error::InvalidPath)
+                );
+
+                // The frontier of the old tree gives the same proof.
+                let appended = &leaves[old_size as usize..new_size as usize];
+                let from_frontier = frontiers[old_size as usize]
+                    .consistency_proof(appended)
+                    .unwrap();
+                assert_eq!(from_frontier.leaf, proof.leaf);
+                assert_eq!(from_frontier.neighbours(), proof.neighbours());
+            }
+        }
+        assert_eq!(
+            frontiers[3].consistency_proof(&[]).unwrap_err(),
+            Error::InvalidRange
+        );
+
+        assert_eq!(
+            merkle.consistency_proof(3, 3).unwrap_err(),
+            Error::InvalidRange
+        );
+        assert_eq!(
+            merkle.consistency_proof(3, 14).unwrap_err(),
+            Error::InvalidRange
+        );
+        assert_eq!(merkle.root_at(14).unwrap_err(), Error::InvalidRange);
+    }
+
+    #[test]
+    fn should_reject_rewritten_history() {
+        let rng = &mut thread_rng();
+        let leaves: Vec<Hash> = (0..6).map(|_| Hash::random(rng)).collect();
+        let mut honest = DenseIncrementalMerkleTree::<Tagged<Keccak256>, 4>::new();
+        let mut forked = DenseIncrementalMerkleTree::<Tagged<Keccak256>, 4>::new();
+        for (i, leaf) in leaves.iter().enumerate() {
+            honest.insert_leaf(leaf.clone()).unwrap();
+            let leaf = if i == 1 {
+                Hash::random(rng)
+            } else {
+                leaf.clone()
+            };
+            forked.insert_leaf(leaf).unwrap();
+        }
+
+        let proof = forked.consistency_proof(4, 6).unwrap();
+        let old_root = honest.root_at(4).unwrap();
+        assert_eq!(
+            proof.verify(&old_root, &forked.root().unwrap()),
+            Err(Error::InvalidPath)
+        );
+    }
+
+    #[test]
+    fn dense_tree_should_issue_witnesses_for_any_leaf() {
+        let rng = &mut thread_rng();
+        let mut merkle = DenseIncrementalMerkleTree::<Tagged<Keccak256>, 5>::new();
+        for _ in 0..11 {
+            merkle.insert_leaf(Hash::random(rng)).unwrap();
+        }
+        let mut witnesses: Vec<_> = (0..11).map(|i| merkle.witness(i).unwrap()).collect();
+        assert_eq!(merkle.witness(11).unwrap_err(), Error::LeafNotFound);
+
+        for _ in 0..9 {
+            let leaf = Hash::random(rng);
+            merkle.insert_leaf(leaf.clone()).unwrap();
+            for witness in witnesses.iter_mut() {
+                witness.append(leaf.clone()).unwrap();
+            }
+        }
+        let root = merkle.root().unwrap();
+        for witness in &witnesses {
+            assert_eq!(witness.root(), root);
+            assert!(witness.path().verify(&root).is_ok());
+        }
+    }
+
+    #[test]
+    fn bulk_build_should_match_sequential_inserts() {
+        let rng = &mut thread_rng();
+        let leaves: Vec<Hash> = (0..77).map(|_| Hash::random(rng)).collect();
+        let mut sequential = DenseIncrementalMerkleTree::<Tagged<Poseidon>, 10>::new();
+        sequential.insert_batch(leaves.clone()).unwrap();
+
+        let bulk = DenseIncrementalMerkleTree::<Tagged<Poseidon>, 10>::from_leaves(leaves.clone())
+            .unwrap();
+        assert_eq!(bulk.root(), sequential.root());
+
+        // Extending an existing tree in uneven chunks.
+        let mut chunked = DenseIncrementalMerkleTree::<Tagged<Poseidon>, 10>::new();
+        chunked.insert_leaf(leaves[0].clone()).unwrap();
+        chunked.extend(leaves[1..30].to_vec()).unwrap();
+        chunked.extend(Vec::new()).unwrap();
+        chunked.extend(leaves[30..].to_vec()).unwrap();
+        assert_eq!(chunked.root(), sequential.root());
+        assert_eq!(chunked.size(), 77);
+        for index in [0, 1, 29, 30, 76] {
+            assert_eq!(
+                chunked.find_path(index).unwrap().neighbours(),
+                sequential.find_path(index).unwrap().neighbours()
+            );
+        }
+    }
+
+    #[test]
+    fn bulk_build_should_respect_capacity() {
+        let mut merkle = DenseIncrementalMerkleTree::<Tagged<Keccak256>, 2>::new();
+        merkle.insert_leaf(Hash::default()).unwrap();
+        assert_eq!(
+            merkle.extend(vec![Hash::default(); 4]),
+            Err(Error::TreeFull)
+        );
+        assert_eq!(merkle.size(), 1);
+        merkle.extend(vec![Hash::default(); 3]).unwrap();
+        assert_eq!(merkle.insert_leaf(Hash::default()), Err(Error::TreeFull));
+    }
+
+    #[test]
+    fn should_rewind_to_checkpoint() {
+        let rng = &mut thread_rng();
+        let leaves: Vec<Hash> = (0..20).map(|_| Hash::random(rng)).collect();
+        let mut merkle = DenseIncrementalMerkleTree::<Tagged<Keccak256>, 5>::new();
+        merkle.checkpoint(0);
+        merkle.extend(leaves[..13].to_vec()).unwrap();
+        merkle.checkpoint(1);
+        merkle.extend(leaves[13..].to_vec()).unwrap();
+        merkle.checkpoint(2);
+        let reference =
+            DenseIncrementalMerkleTree::<Tagged<Keccak256>, 5>::from_leaves(leaves[..13].to_vec())
+                .unwrap();
+
+        merkle.rewind_to(1).unwrap();
+        assert_eq!(merkle.size(), 13);
+        assert_eq!(merkle.root(), reference.root());
+        assert_eq!(merkle.find_path(13).unwrap_err(), Error::LeafNotFound);
+        for index in 0..13 {
+            assert_eq!(
+                merkle.find_path(index).unwrap().neighbours(),
+                reference.find_path(index).unwrap().neighbours()
+            );
+        }
+        assert_eq!(merkle.rewind_to(2), Err(Error::CheckpointNotFound));
+
+        // The rewound tree keeps growing like the original one.
+        merkle.extend(leaves[13..].to_vec()).unwrap();
+        let full = DenseIncrementalMerkleTree::<Tagged<Keccak256>, 5>::from_leaves(leaves).unwrap();
+        assert_eq!(merkle.root(), full.root());
+
+        merkle.rewind_to(0).unwrap();
+        assert_eq!(
+            merkle.root(),
+            DenseIncrementalMerkleTree::<Tagged<Keccak256>, 5>::new().root()
+        );
+    }
+
+    #[test]
+    fn sparse_tree_should_remove_keys() {
+        let rng = &mut thread_rng();
+        let mut set = SparseMerkleTree::<Tagged<Keccak256>>::new();
+        let kept = Hash::random(rng);
+        set.insert(kept.clone());
+        let root = set.root();
+
+        let removed = Hash::random(rng);
+        set.insert(removed.clone());
+        assert!(set.remove(&removed));
+        assert!(!set.remove(&removed));
+        assert_eq!(set.root(), root);
+        assert!(set.find_path(&removed).verify_non_membership(&root).is_ok());
+
+        assert!(set.remove(&kept));
+        assert_eq!(
+            set.root(),
+            SparseMerkleTree::<Tagged<Keccak256>>::new().root()
+        );
+        assert_eq!(set.keys().count(), 0);
+    }
+
+    #[test]
+    fn path_should_round_trip_compact_bytes() {
+        let rng = &mut thread_rng();
+        let mut merkle = DenseIncrementalMerkleTree::<Tagged<Poseidon>, 20>::new();
+        for _ in 0..5 {
+            merkle.insert_leaf(Hash::random(rng)).unwrap();
+        }
+        let root = merkle.root().unwrap();
+
+        for index in [0, 3, 4] {
+            let path = merkle.find_path(index).unwrap();
+            let bytes = path.to_bytes();
+            // Only the siblings below the fifth leaf are not empty subtrees.
+            let stored = path.neighbours().len()
+                - bytes[42..45]
+                    .iter()
+                    .map(|b| b.count_ones() as usize)
+                    .sum::<usize>();
+            assert_eq!(bytes.len(), 42 + 3 + 32 * stored);
+            assert!(stored <= 3);
+
+            let decoded = Path::<Tagged<Poseidon>>::from_bytes(&bytes).unwrap();
+            assert_eq!(decoded.index(), path.index());
+            assert_eq!(decoded.leaf(), path.leaf());
+            assert_eq!(decoded.neighbours(), path.neighbours());
+            assert!(decoded.verify(&root).is_ok());
+        }
+
+        let bytes = merkle.find_path(3).unwrap().to_bytes();
+        let mut future = bytes.clone();
+        future[0] = 2;
+        for bad in [&future[..], &bytes[..bytes.len() - 1], &bytes[..10]] {
+            assert_eq!(
+                Path::<Tagged<Poseidon>>::from_bytes(bad).unwrap_err(),
+                Error::InvalidPath
+            );
+        }
+        let mut longer = bytes.clone();
+        longer.push(0);
+        assert!(Path::<Tagged<Poseidon>>::from_bytes(&longer).is_err());
+        // Bitmap bits past the 20 levels are padding.
+        let mut padded = bytes.clone();
+        padded[44] |= 0x80;
+        assert_eq!(
+            Path::<Tagged<Poseidon>>::from_bytes(&padded).unwrap_err(),
+            Error::InvalidPath
+        );
+    }
+
+    #[test]
+    fn path_should_round_trip_serde() {
+        let rng = &mut thread_rng();
+        let mut merkle = DenseIncrementalMerkleTree::<Tagged<Keccak256>, 6>::new();
+        merkle.insert_leaf(Hash::random(rng)).unwrap();
+        let path = merkle.find_path(0).unwrap();
+        let json = serde_json::to_string(&path).unwrap();
+        let decoded: Path<Tagged<Keccak256>> = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded.to_bytes(), path.to_bytes());
+
+        // Same shape checks as `from_bytes`.
+        let value = serde_json::to_value(&path).unwrap();
+        let mut deep = value.clone();
+        deep["neighbours"] = serde_json::Value::Array(vec![value["leaf"].clone(); 64]);
+        let mut outside = value;
+        outside["index"] = 64.into();
+        for bad in [deep, outside] {
+            assert!(serde_json::from_value::<Path<Tagged<Keccak256>>>(bad).is_err());
+        }
+    }
+
+    #[test]
+    fn multi_path_should_share_siblings() {
+        let rng = &mut thread_rng();
+        let mut merkle = DenseIncrementalMerkleTree::<Tagged<Keccak256>, 8>::new();
+        for _ in 0..40 {
+            merkle.insert_leaf(Hash::random(rng)).unwrap();
+        }
+        let root = merkle.root().unwrap();
+
+        let multi_path = merkle.find_multi_path(&[9, 3, 2, 33, 9]).unwrap();
+        assert!(multi_path.verify(&root).is_ok());
+        let indices: Vec<u64> = multi_path.leaves().iter().map(|(i, _)| *i).collect();
+        assert_eq!(indices, vec![2, 3, 9, 33]);
+        for (index, leaf) in multi_path.leaves() {
+            assert_eq!(leaf, merkle.find_path(*index).unwrap().leaf());
+        }
+        // Leaves 2 and 3 are siblings, and every path meets the others by
+        // level 6; separate paths would need 4 * 8 siblings.
+        assert_eq!(multi_path.siblings().len(), 13);
+
+        let single = merkle.find_multi_path(&[5]).unwrap();
+        assert_eq!(single.siblings(), merkle.find_path(5).unwrap().neighbours());
+        assert!(single.verify(&root).is_ok());
+
+        assert_eq!(
+            merkle.find_multi_path(&[]).unwrap_err(),
+            Error::InvalidRange
+        );
+        assert_eq!(
+            merkle.find_multi_path(&[1, 40]).unwrap_err(),
+            Error::LeafNotFound
+        );
+    }
+
+    #[test]
+    fn multi_path_should_reject_tampering() {
+        let rng = &mut thread_rng();
+        let mut merkle = DenseIncrementalMerkleTree::<Tagged<Keccak256>, 5>::new();
+        for _ in 0..12 {
+            merkle.insert_leaf(Hash::random(rng)).unwrap();
+        }
+        let root = merkle.root().unwrap();
+        let multi_path = merkle.find_multi_path(&[1, 6, 7]).unwrap();
+
+        let mut forged = multi_path.clone();
+        forged.leaves[1].1 = Hash::random(rng);
+        assert_eq!(forged.verify(&root), Err(Error::InvalidPath));
+
+        let mut short = multi_path.clone();
+        short.siblings.pop();
+        assert_eq!(short.verify(&root), Err(Error::InvalidPath));
+
+        let mut long = multi_path.clone();
+        long.siblings.push(Hash::random(rng));
+        assert_eq!(long.verify(&root), Err(Error::InvalidPath));
+
+        let mut unsorted = multi_path;
+        unsorted.leaves.swap(0, 2);
+        assert_eq!(unsorted.verify(&root), Err(Error::InvalidPath));
+    }
 }
@@ -0,0 +1,246 @@
+use crate::{hasher::MerkleHasher, merkle::Path, Error as MerkleError, Hash};
+use std::{collections::BTreeMap, marker::PhantomData};
+
+/// Merkle Mountain Range: an append-only list of perfect trees ("peaks"),
+/// one per set bit of the size, largest first. It has no capacity, and paths
+/// are at most as long as the bit length of the size.
+///
+/// The root bags the peaks from the right, `node(p0, node(p1, ... pk))`,
+/// with the same `hash_node` as inside the peaks. A path through a peak and
+/// then the bag is therefore an ordinary `Path`, where bit `i` of the index
+/// tells whether the `i`th neighbour is on the left, and it verifies with
+/// `Path::verify` like the paths of the fixed-depth trees.
+#[derive(Clone, Debug)]
+pub struct MerkleMountainRange<H>
+where
+    H: MerkleHasher,
+{
+    leaves: Vec<Hash>,
+    /// Nodes of the complete subtrees of each level: `nodes[h][i]` covers
+    /// leaves `i << h..(i + 1) << h`.
+    nodes: Vec<Vec<Hash>>,
+    checkpoints: BTreeMap<u64, u64>,
+    _h: PhantomData<H>,
+}
+
+impl<H> Default for MerkleMountainRange<H>
+where
+    H: MerkleHasher,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<H> MerkleMountainRange<H>
+where
+    H: MerkleHasher,
+{
+    pub fn new() -> Self {
+        Self {
+            leaves: Vec::new(),
+            nodes: vec![Vec::new()],
+            checkpoints: BTreeMap::new(),
+            _h: PhantomData,
+        }
+    }
+
+    /// Bagged root of the peaks, or the empty leaf for an empty range.
+    pub fn root(&self) -> Result<Hash, MerkleError> {
+        Ok(Self::bag(&self.peaks()).unwrap_or_else(H::empty_leaf))
+    }
+
+    /// Number of leaves inserted so far.
+    pub fn size(&self) -> u64 {
+        self.leaves.len() as u64
+    }
+
+    /// Leaf values from index `from` onwards, in insertion order.
+    pub fn leaves(&self, from: u64) -> Result<Vec<Hash>, MerkleError> {
+        Ok(self
+            .leaves
+            .get(from as usize..)
+            .unwrap_or_default()
+            .to_vec())
+    }
+
+    /// Roots of the perfect trees, from the largest to the smallest.
+    pub fn peaks(&self) -> Vec<Hash> {
+        self.peak_positions()
+            .into_iter()
+            .map(|(height, index)| self.nodes[height as usize][index as usize].clone())
+            .collect()
+    }
+
+    pub fn insert_leaf(&mut self, leaf: Hash) -> Result<u64, MerkleError> {
+        let index = self.size();
+        self.nodes[0].push(H::hash_leaf(&leaf));
+        self.leaves.push(leaf);
+
+        let mut height = 0;
+        while self.nodes[height].len() % 2 == 0 {
+            let level = &self.nodes[height];
+            let node = H::hash_node(&level[level.len() - 2], &level[level.len() - 1]);
+            if self.nodes.len() == height + 1 {
+                self.nodes.push(Vec::new());
+            }
+            self.nodes[height + 1].push(node);
+            height += 1;
+        }
+        Ok(index)
+    }
+
+    /// Path of leaf `index` against the current root: its siblings inside
+    /// its peak, then the bag of the smaller peaks, if any, then each larger
+    /// peak. `Path::index` holds the directions, not the leaf index.
+    pub fn find_path(&self, index: u64) -> Result<Path<H>, MerkleError> {
+        let leaf = self
+            .leaves
+            .get(index as usize)
+            .cloned()
+            .ok_or(MerkleError::LeafNotFound)?;
+
+        let positions = self.peak_positions();
+        let peak = positions
+            .iter()
+            .position(|(height, peak)| index >> height == *peak)
+            .ok_or(MerkleError::LeafNotFound)?;
+        let height = positions[peak].0;
+
+        let mut neighbours = Vec::new();
+        let mut directions = index & ((1 << height) - 1);
+        for level in 0..height {
+            let sibling = (index >> level) ^ 1;
+            neighbours.push(self.nodes[level as usize][sibling as usize].clone());
+        }
+        let peaks = self.peaks();
+        if let Some(bag) = Self::bag(&peaks[peak + 1..]) {
+            neighbours.push(bag);
+        }
+        for larger in peaks[..peak].iter().rev() {
+            directions |= 1 << neighbours.len();
+            neighbours.push(larger.clone());
+        }
+
+        Ok(Path::new(directions, neighbours, leaf))
+    }
+
+    /// Records the current size under `id`, e.g. a block height. Ids are
+    /// expected to increase; an existing id is overwritten.
+    pub fn checkpoint(&mut self, id: u64) {
+        self.checkpoints.insert(id, self.size());
+    }
+
+    /// Drops every leaf inserted after checkpoint `id`, and every later
+    /// checkpoint. The checkpoint itself is kept.
+    pub fn rewind_to(&mut self, id: u64) -> Result<(), MerkleError> {
+        let size = *self
+            .checkpoints
+            .get(&id)
+            .ok_or(MerkleError::CheckpointNotFound)?;
+        self.checkpoints.split_off(&(id + 1));
+
+        self.leaves.truncate(size as usize);
+        for (height, level) in self.nodes.iter_mut().enumerate() {
+            level.truncate((size >> height) as usize);
+        }
+        Ok(())
+    }
+
+    /// Height and index within its level of each peak, largest first.
+    fn peak_positions(&self) -> Vec<(u32, u64)> {
+        let size = self.size();
+        (0..u64::BITS)
+            .rev()
+            .filter(|height| size >> height & 1 == 1)
+            .map(|height| (height, (size >> height) - 1))
+            .collect()
+    }
+
+    fn bag(peaks: &[Hash]) -> Option<Hash> {
+        peaks
+            .iter()
+            .rev()
+            .cloned()
+            .reduce(|bag, peak| H::hash_node(&peak, &bag))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::MerkleMountainRange;
+    use crate::hasher::{MerkleHasher, Tagged};
+    use crate::merkle::DenseIncrementalMerkleTree;
+    use crate::{Error, Hash};
+    use rand::thread_rng;
+    use sha3::Keccak256;
+
+    type Hasher = Tagged<Keccak256>;
+
+    #[test]
+    fn paths_should_verify_at_every_size() {
+        let rng = &mut thread_rng();
+        let mut mmr = MerkleMountainRange::<Hasher>::new();
+        assert_eq!(mmr.root().unwrap(), Hasher::empty_leaf());
+
+        for size in 1..=40u64 {
+            mmr.insert_leaf(Hash::random(rng)).unwrap();
+            let root = mmr.root().unwrap();
+            assert_eq!(mmr.peaks().len() as u32, size.count_ones());
+
+            for index in 0..size {
+                let path = mmr.find_path(index).unwrap();
+                assert_eq!(path.leaf(), &mmr.leaves(index).unwrap()[0]);
+                assert!(path.neighbours().len() as u32 <= u64::BITS - size.leading_zeros());
+                assert!(path.verify(&root).is_ok());
+            }
+        }
+        assert_eq!(mmr.find_path(40).unwrap_err(), Error::LeafNotFound);
+    }
+
+    #[test]
+    fn single_peak_should_match_dense_tree() {
+        let rng = &mut thread_rng();
+        let leaves: Vec<Hash> = (0..8).map(|_| Hash::random(rng)).collect();
+        let dense = DenseIncrementalMerkleTree::<Hasher, 3>::from_leaves(leaves.clone()).unwrap();
+
+        let mut mmr = MerkleMountainRange::<Hasher>::new();
+        for leaf in leaves {
+            mmr.insert_leaf(leaf).unwrap();
+        }
+        assert_eq!(mmr.root(), dense.root());
+        assert_eq!(
+            mmr.find_path(5).unwrap().neighbours(),
+            dense.find_path(5).unwrap().neighbours()
+        );
+    }
+
+    #[test]
+    fn should_rewind_to_checkpoint() {
+        let rng = &mut thread_rng();
+        let mut mmr = MerkleMountainRange::<Hasher>::new();
+        for _ in 0..5 {
+            mmr.insert_leaf(Hash::random(rng)).unwrap();
+        }
+        mmr.checkpoint(1);
+        let root = mmr.root().unwrap();
+        for _ in 0..6 {
+            mmr.insert_leaf(Hash::random(rng)).unwrap();
+        }
+        mmr.checkpoint(2);
+
+        mmr.rewind_to(1).unwrap();
+        assert_eq!(mmr.size(), 5);
+        assert_eq!(mmr.root().unwrap(), root);
+        assert_eq!(mmr.rewind_to(2), Err(Error::CheckpointNotFound));
+
+        // Appending after a rewind rebuilds the same peaks.
+        let leaf = Hash::random(rng);
+        mmr.insert_leaf(leaf.clone()).unwrap();
+        let mut fresh = MerkleMountainRange::<Hasher>::new();
+        for leaf in mmr.leaves(0).unwrap() {
+            fresh.insert_leaf(leaf).unwrap();
+        }
+        assert_eq!(mmr.root(), fresh.root());
+    }
+}
@@ -0,0 +1,360 @@
+use crate::{
+    hasher::Domain,
+    pool::{NotePath, Payout, TREE_DEPTH},
+    poseidon::hash,
+    zk::{
+        self, TransferCircuit, TransferProof, TransferProvingKey, WithdrawCircuit, WithdrawProof,
+        WithdrawProvingKey, TRANSFER_INPUTS, TRANSFER_OUTPUTS,
+    },
+    Error, Field, Hash,
+};
+use ark_std::rand::{CryptoRng, RngCore};
+use serde::{Deserialize, Deserializer, Serialize};
+
+/// Current version of the `Note` byte layout.
+pub const NOTE_VERSION: u8 = 4;
+
+/// Length of `Note::to_bytes`: version, three field elements and three u64.
+const NOTE_BYTES: usize = 1 + 32 * 3 + 8 * 3;
+
+/// Secret that authorises spending notes paid to its public key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SpendingKey(Field);
+
+impl SpendingKey {
+    pub fn new(key: Field) -> Self {
+        Self(key)
+    }
+
+    pub fn random<R: RngCore + CryptoRng>(rng: &mut R) -> Self {
+        Self(Field::random(rng))
+    }
+
+    /// `H(PUBLIC_KEY, sk)`
+    pub fn public_key(&self) -> Field {
+        hash(&[Domain::PublicKey.to_field(), self.0])
+    }
+
+    pub fn inner(&self) -> &Field {
+        &self.0
+    }
+}
+
+/// Opening of a note commitment. Built and kept by the wallet; the pool only
+/// ever sees its commitment.
+///
+/// - `commitment = H(COMMITMENT, nullifier_key, randomness, amount, asset, recipient_pk)`
+/// - `nullifier = H(NULLIFIER, nullifier_key, commitment, asset)`
+///
+/// `amount` is the denomination of the pool the note is deposited into, in
+/// units of `asset`. Where it is paid out is only chosen when it is spent,
+/// so neither the note nor its deposit says anything about the withdrawal.
+/// Tags are the `Domain`s shared with the tree hasher. The `epoch` of the
+/// pool tree holding the commitment is not committed to; the wallet records
+/// it with `set_epoch` once the deposit is in.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Note {
+    version: u8,
+    nullifier_key: Field,
+    randomness: Field,
+    amount: u64,
+    asset: u64,
+    recipient_pk: Field,
+    epoch: u64,
+}
+
+impl<'de> Deserialize<'de> for Note {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(rename = "Note")]
+        struct Fields {
+            version: u8,
+            nullifier_key: Field,
+            randomness: Field,
+            amount: u64,
+            asset: u64,
+            recipient_pk: Field,
+            epoch: u64,
+        }
+
+        let fields = Fields::deserialize(deserializer)?;
+        if fields.version != NOTE_VERSION {
+            return Err(serde::de::Error::custom(Error::InvalidNote));
+        }
+        Ok(Self {
+            version: fields.version,
+            nullifier_key: fields.nullifier_key,
+            randomness: fields.randomness,
+            amount: fields.amount,
+            asset: fields.asset,
+            recipient_pk: fields.recipient_pk,
+            epoch: fields.epoch,
+        })
+    }
+}
+
+impl Note {
+    pub fn new(
+        nullifier_key: Field,
+        randomness: Field,
+        amount: u64,
+        asset: u64,
+        recipient_pk: Field,
+    ) -> Self {
+        Self {
+            version: NOTE_VERSION,
+            nullifier_key,
+            randomness,
+            amount,
+            asset,
+            recipient_pk,
+            epoch: 0,
+        }
+    }
+
+    /// Note with a fresh nullifier key and blinding randomness.
+    pub fn random<R: RngCore + CryptoRng>(
+        rng: &mut R,
+        amount: u64,
+        asset: u64,
+        recipient_pk: Field,
+    ) -> Self {
+        Self::new(
+            Field::random(rng),
+            Field::random(rng),
+            amount,
+            asset,
+            recipient_pk,
+        )
+    }
+
+    pub fn commitment(&self) -> Hash {
+        self.commitment_field().to_hash()
+    }
+
+    pub fn nullifier(&self) -> Hash {
+        hash(&[
+            Domain::Nullifier.to_field(),
+            self.nullifier_key,
+            self.commitment_field(),
+            Field::from(self.asset),
+        ])
+        .to_hash()
+    }
+
+    fn commitment_field(&self) -> Field {
+        hash(&[
+            Domain::Commitment.to_field(),
+            self.nullifier_key,
+            self.randomness,
+            Field::from(self.amount),
+            Field::from(self.asset),
+            self.recipient_pk,
+        ])
+    }
+
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    pub fn nullifier_key(&self) -> &Field {
+        &self.nullifier_key
+    }
+
+    pub fn randomness(&self) -> &Field {
+        &self.randomness
+    }
+
+    pub fn amount(&self) -> u64 {
+        self.amount
+    }
+
+    pub fn asset(&self) -> u64 {
+        self.asset
+    }
+
+    pub fn recipient_pk(&self) -> &Field {
+        &self.recipient_pk
+    }
+
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    pub fn set_epoch(&mut self, epoch: u64) {
+        self.epoch = epoch;
+    }
+
+    /// `version || nullifier_key || randomness || amount || asset || recipient_pk || epoch`,
+    /// field elements big-endian, integers big-endian.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(NOTE_BYTES);
+        bytes.push(self.version);
+        bytes.extend_from_slice(self.nullifier_key.to_hash().inner());
+        bytes.extend_from_slice(self.randomness.to_hash().inner());
+        bytes.extend_from_slice(&self.amount.to_be_bytes());
+        bytes.extend_from_slice(&self.asset.to_be_bytes());
+        bytes.extend_from_slice(self.recipient_pk.to_hash().inner());
+        bytes.extend_from_slice(&self.epoch.to_be_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        if bytes.len() != NOTE_BYTES || bytes[0] != NOTE_VERSION {
+            return Err(Error::InvalidNote);
+        }
+        let field = |offset: usize| {
+            let mut buf = [0; 32];
+            buf.copy_from_slice(&bytes[offset..offset + 32]);
+            Field::from_hash(&Hash(buf))
+        };
+        let int = |offset: usize| {
+            let mut buf = [0; 8];
+            buf.copy_from_slice(&bytes[offset..offset + 8]);
+            u64::from_be_bytes(buf)
+        };
+
+        Ok(Self {
+            version: bytes[0],
+            nullifier_key: field(1)?,
+            randomness: field(33)?,
+            amount: int(65),
+            asset: int(73),
+            recipient_pk: field(81)?,
+            epoch: int(113),
+        })
+    }
+
+    /// Proves ownership of the note for a withdrawal to `payout`, without
+    /// revealing which commitment is being spent. The proof is against the
+    /// root of `merkle_path`.
+    pub fn prove<R: RngCore + CryptoRng>(
+        &self,
+        spending_key: &SpendingKey,
+        merkle_path: &NotePath,
+        payout: &Payout,
+        proving_key: &WithdrawProvingKey,
+        rng: &mut R,
+    ) -> Result<WithdrawProof, Error> {
+        if merkle_path.neighbours().len() > TREE_DEPTH {
+            return Err(Error::InvalidPath);
+        }
+        let circuit = WithdrawCircuit::new(self, spending_key, merkle_path, payout);
+        Ok(zk::prove(proving_key, circuit, rng)?)
+    }
+}
+
+/// Proves a transfer of the `inputs`, all paid to `spending_key` and proven
+/// against the same root, into the `outputs`. The notes must share an asset,
+/// and the outputs must be worth as much as the inputs.
+pub fn prove_transfer<R: RngCore + CryptoRng>(
+    spending_key: &SpendingKey,
+    inputs: &[(Note, NotePath)],
+    outputs: &[Note],
+    proving_key: &TransferProvingKey,
+    rng: &mut R,
+) -> Result<TransferProof, Error> {
+    if !(1..=TRANSFER_INPUTS).contains(&inputs.len())
+        || !(1..=TRANSFER_OUTPUTS).contains(&outputs.len())
+    {
+        return Err(Error::InvalidTransfer);
+    }
+    let (first, first_path) = &inputs[0];
+    let root = first_path.construct_root();
+    for (_, path) in inputs {
+        if path.neighbours().len() > TREE_DEPTH || path.construct_root() != root {
+            return Err(Error::InvalidPath);
+        }
+    }
+    let mut notes = inputs.iter().map(|(note, _)| note).chain(outputs);
+    if notes.any(|note| note.asset() != first.asset()) {
+        return Err(Error::InvalidTransfer);
+    }
+    let value_in: u128 = inputs.iter().map(|(note, _)| note.amount() as u128).sum();
+    let value_out: u128 = outputs.iter().map(|note| note.amount() as u128).sum();
+    if value_in != value_out {
+        return Err(Error::InvalidTransfer);
+    }
+
+    let circuit = TransferCircuit::new(spending_key, inputs, outputs);
+    Ok(zk::prove_transfer(proving_key, circuit, rng)?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Note, SpendingKey, NOTE_VERSION};
+    use crate::{Error, Field};
+    use rand::thread_rng;
+
+    fn fixed_note() -> Note {
+        let spending_key = SpendingKey::new(Field::from(1));
+        Note::new(
+            Field::from(2),
+            Field::from(3),
+            1000,
+            0,
+            spending_key.public_key(),
+        )
+    }
+
+    #[test]
+    fn should_match_note_vectors() {
+        let note = fixed_note();
+        assert_eq!(
+            note.recipient_pk().to_hash().to_hex(),
+            "007af346e2d304279e79e0a9f3023f771294a78acb70e73f90afe27cad401e81"
+        );
+        assert_eq!(
+            note.commitment().to_hex(),
+            "02bbbd0d65718515baaf1c67ac74e3dfd11e49605274d565028beede71a83cd9"
+        );
+        assert_eq!(
+            note.nullifier().to_hex(),
+            "18723cad87499d60c8b114e773a398cbaa3601a792cd159c8fed37febe954bc5"
+        );
+    }
+
+    #[test]
+    fn should_round_trip_bytes() {
+        let rng = &mut thread_rng();
+        let pk = SpendingKey::random(rng).public_key();
+        let mut note = Note::random(rng, 1000, 0, pk);
+        note.set_epoch(7);
+        let bytes = note.to_bytes();
+        assert_eq!(bytes[0], NOTE_VERSION);
+        assert_eq!(Note::from_bytes(&bytes).unwrap(), note);
+
+        let mut future = bytes.clone();
+        future[0] = NOTE_VERSION + 1;
+        assert_eq!(Note::from_bytes(&future), Err(Error::InvalidNote));
+        assert_eq!(Note::from_bytes(&bytes[1..]), Err(Error::InvalidNote));
+    }
+
+    #[test]
+    fn should_reject_other_versions_when_deserializing() {
+        let rng = &mut thread_rng();
+        let pk = SpendingKey::random(rng).public_key();
+        let note = Note::random(rng, 1000, 0, pk);
+        let json = serde_json::to_value(&note).unwrap();
+        assert_eq!(serde_json::from_value::<Note>(json.clone()).unwrap(), note);
+
+        let mut future = json;
+        future["version"] = (NOTE_VERSION + 1).into();
+        assert!(serde_json::from_value::<Note>(future).is_err());
+    }
+
+    #[test]
+    fn random_notes_should_not_collide() {
+        let rng = &mut thread_rng();
+        let pk = SpendingKey::random(rng).public_key();
+        let a = Note::random(rng, 1000, 0, pk);
+        let b = Note::random(rng, 1000, 0, pk);
+        assert_ne!(a.commitment(), b.commitment());
+        assert_ne!(a.nullifier(), b.nullifier());
+
+        // The same opening in another asset is another note.
+        let c = Note::new(*a.nullifier_key(), *a.randomness(), 1000, 1, pk);
+        assert_ne!(a.commitment(), c.commitment());
+        assert_ne!(a.nullifier(), c.nullifier());
+    }
+}
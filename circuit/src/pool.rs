@@ -1,145 +1,1605 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 
 use crate::{
-    hash_leaf, hash_two,
-    merkle::{self, DenseIncrementalMerkleTree},
-    Hash,
+    hasher::Tagged,
+    merkle::{
+        self, DenseIncrementalMerkleTree, FrontierMerkleTree, IncrementalWitness, SparseMerkleTree,
+    },
+    mmr::MerkleMountainRange,
+    poseidon::Poseidon,
+    zk::{
+        self, TransferProof, TransferVerifyingKey, WithdrawProof, WithdrawVerifyingKey,
+        TRANSFER_INPUTS, TRANSFER_OUTPUTS,
+    },
+    Error, Field, Hash,
 };
-use serde::Serialize;
-use sha3::Keccak256;
+use ark_bn254::Bn254;
+use ark_groth16::{prepare_verifying_key, PreparedVerifyingKey};
+use serde::{Deserialize, Serialize};
+
+pub use crate::note::{Note, SpendingKey};
 
 const CONTRACT_ADDRESS: u64 = 123948573;
 const DEFAULT_ACCOUNT: u64 = 123;
 const DEFAULT_AMOUNT: u64 = 1000;
 
-#[derive(Debug, Clone, Serialize)]
-pub struct Note {
-    secret: u64,
-    topic: u64,
-    recipiant: u64,
-    merkle_path: merkle::Path,
+/// Asset id of the native coin.
+pub const NATIVE_ASSET: u64 = 0;
+
+/// Depth of the commitment tree, matching common on-chain deployments.
+pub const TREE_DEPTH: usize = 20;
+
+/// Number of recent roots a withdrawal proof may refer to.
+pub const DEFAULT_ROOT_HISTORY_SIZE: usize = 100;
+
+/// Domain-separated Poseidon, used for every tree of the pool.
+pub type PoolHasher = Tagged<Poseidon>;
+
+/// Merkle path of a commitment in the pool tree.
+pub type NotePath = merkle::Path<PoolHasher>;
+
+/// Proof that a nullifier is, or is not, in the spent set.
+pub type NullifierPath = merkle::SparsePath<PoolHasher>;
+
+/// Witness a wallet keeps for each of its notes to stay in sync with the pool
+/// tree.
+pub type NoteWitness = IncrementalWitness<PoolHasher, TREE_DEPTH>;
+
+/// Proof that a later pool root only appended commitments to an earlier one.
+pub type PoolConsistencyProof = merkle::ConsistencyProof<PoolHasher>;
+
+/// Ledger balances by `(account, asset)`.
+pub type Balances = HashMap<(u64, u64), u64>;
+
+/// Where a withdrawal pays out. Chosen by the spender when proving and
+/// bound into the proof, not into the note.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Payout {
+    pub recipient: u64,
+    /// Account submitting the withdrawal on behalf of the recipient.
+    pub relayer: u64,
+    /// Part of the amount paid to the relayer rather than the recipient.
+    pub fee: u64,
 }
 
-impl Note {
-    pub fn commitment(&self) -> Hash {
-        let secret_hash = hash_leaf::<Keccak256>(self.secret.to_be_bytes().to_vec());
-        hash_two::<Keccak256>(secret_hash.clone(), secret_hash)
+impl Payout {
+    /// Payout of the whole amount to `recipient`.
+    pub fn new(recipient: u64) -> Self {
+        Self {
+            recipient,
+            relayer: 0,
+            fee: 0,
+        }
     }
 
-    pub fn nullifier(&self) -> Hash {
-        let secret_hash = hash_leaf::<Keccak256>(self.secret.to_be_bytes().to_vec());
-        let topic_hash = hash_leaf::<Keccak256>(self.topic.to_be_bytes().to_vec());
-        hash_two::<Keccak256>(secret_hash.clone(), topic_hash)
+    /// Pays `fee` of the amount to `relayer` instead of the recipient.
+    pub fn with_relayer(mut self, relayer: u64, fee: u64) -> Self {
+        self.relayer = relayer;
+        self.fee = fee;
+        self
     }
+}
 
-    pub fn recipiant(&self) -> u64 {
-        self.recipiant
-    }
+/// Public data of a withdrawal: everything its proof is checked against,
+/// apart from the denomination of the pool.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Withdrawal {
+    pub asset: u64,
+    /// Epoch of the tree holding the spent commitment.
+    pub epoch: u64,
+    pub root: Hash,
+    pub nullifier_hash: Hash,
+    pub payout: Payout,
 }
 
-pub struct AnonymityPool {
-    tree: DenseIncrementalMerkleTree<Keccak256>,
-    nullifiers: HashMap<Hash, bool>,
-    balances: HashMap<u64, u64>,
-    root_history: Vec<Hash>,
+/// Public data of a join-split transfer inside the pool: the nullifiers of
+/// the spent notes and the commitments of the new ones, all of `asset`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Transfer {
+    pub asset: u64,
+    /// Epoch of the tree holding the spent commitments.
+    pub epoch: u64,
+    pub root: Hash,
+    pub nullifiers: Vec<Hash>,
+    pub commitments: Vec<Hash>,
 }
 
-impl Default for AnonymityPool {
+/// Fixed-size ring buffer of the most recent tree roots and the number of
+/// leaves behind each, with a count index for constant time lookups.
+#[derive(Debug, Clone)]
+pub struct RootHistory {
+    roots: Vec<(Hash, u64)>,
+    next: usize,
+    counts: HashMap<Hash, usize>,
+    size: usize,
+}
+
+impl RootHistory {
+    /// History keeping the last `size` roots, at least one.
+    pub fn new(size: usize) -> Self {
+        let size = size.max(1);
+        Self {
+            roots: Vec::with_capacity(size),
+            next: 0,
+            counts: HashMap::new(),
+            size,
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn len(&self) -> usize {
+        self.roots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.roots.is_empty()
+    }
+
+    /// Records the root of a tree of `leaves` leaves, evicting the oldest
+    /// root once the history is full.
+    pub fn push(&mut self, root: Hash, leaves: u64) {
+        *self.counts.entry(root.clone()).or_insert(0) += 1;
+        if self.roots.len() < self.size {
+            self.roots.push((root, leaves));
+        } else {
+            let (evicted, _) = std::mem::replace(&mut self.roots[self.next], (root, leaves));
+            if let Some(count) = self.counts.get_mut(&evicted) {
+                *count -= 1;
+                if *count == 0 {
+                    self.counts.remove(&evicted);
+                }
+            }
+        }
+        self.next = (self.next + 1) % self.size;
+    }
+
+    pub fn contains(&self, root: &Hash) -> bool {
+        self.counts.contains_key(root)
+    }
+
+    pub fn latest(&self) -> Option<&Hash> {
+        let last = (self.next + self.size - 1) % self.size;
+        self.roots.get(last).map(|(root, _)| root)
+    }
+
+    /// Number of leaves behind a known root.
+    pub fn leaves(&self, root: &Hash) -> Option<u64> {
+        if !self.contains(root) {
+            return None;
+        }
+        self.roots
+            .iter()
+            .find(|(known, _)| known == root)
+            .map(|(_, leaves)| *leaves)
+    }
+}
+
+impl Default for RootHistory {
     fn default() -> Self {
-        Self::new()
+        Self::new(DEFAULT_ROOT_HISTORY_SIZE)
+    }
+}
+
+/// Accumulator holding the commitments of a pool.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Accumulator {
+    /// Frontier of a tree of `TREE_DEPTH` levels and the log of its
+    /// commitments. Paths and consistency proofs are rebuilt from the log.
+    #[default]
+    Frontier,
+    /// Tree of `TREE_DEPTH` levels keeping every node, so paths and
+    /// consistency proofs are read off directly at the cost of memory.
+    Dense,
+    /// Merkle Mountain Range, whose paths grow with the number of deposits.
+    MountainRange,
+}
+
+/// O(depth) tree frontier plus the commitments appended to it. The frontiers
+/// after the most recent insertions are kept, so paths of recent notes and
+/// consistency proofs against recent roots only hash the later commitments.
+#[derive(Debug, Clone)]
+struct CommitmentLog {
+    tree: FrontierMerkleTree<PoolHasher, TREE_DEPTH>,
+    commitments: Vec<Hash>,
+    frontiers: BTreeMap<u64, FrontierMerkleTree<PoolHasher, TREE_DEPTH>>,
+    snapshots: usize,
+    checkpoints: BTreeMap<u64, FrontierMerkleTree<PoolHasher, TREE_DEPTH>>,
+}
+
+impl CommitmentLog {
+    /// Log keeping the frontiers of its last `snapshots` sizes.
+    fn new(snapshots: usize) -> Self {
+        Self {
+            tree: FrontierMerkleTree::new(),
+            commitments: Vec::new(),
+            frontiers: BTreeMap::new(),
+            snapshots,
+            checkpoints: BTreeMap::new(),
+        }
+    }
+
+    fn insert_leaf(&mut self, leaf: Hash) -> Result<u64, Error> {
+        let index = self.tree.insert_leaf(leaf.clone())?;
+        self.commitments.push(leaf);
+        self.frontiers.insert(self.tree.size(), self.tree.clone());
+        while self.frontiers.len() > self.snapshots {
+            self.frontiers.pop_first();
+        }
+        Ok(index)
+    }
+
+    /// Frontier of the first `size` commitments, replayed from the log when
+    /// it is older than the kept snapshots.
+    fn frontier(&self, size: u64) -> Result<FrontierMerkleTree<PoolHasher, TREE_DEPTH>, Error> {
+        if let Some(tree) = self.frontiers.get(&size) {
+            return Ok(tree.clone());
+        }
+        let mut tree = FrontierMerkleTree::new();
+        for leaf in &self.commitments[..size as usize] {
+            tree.insert_leaf(leaf.clone())?;
+        }
+        Ok(tree)
+    }
+
+    /// Witness of any logged commitment. The latest one is taken off the
+    /// frontier.
+    fn witness(&self, index: u64) -> Result<NoteWitness, Error> {
+        if index >= self.tree.size() {
+            return Err(Error::LeafNotFound);
+        }
+        if index + 1 == self.tree.size() {
+            return IncrementalWitness::from_tree(&self.tree);
+        }
+        let mut witness = IncrementalWitness::from_tree(&self.frontier(index + 1)?)?;
+        for leaf in &self.commitments[index as usize + 1..] {
+            witness.append(leaf.clone())?;
+        }
+        Ok(witness)
+    }
+
+    fn find_path(&self, index: u64) -> Result<NotePath, Error> {
+        if index + 1 == self.tree.size() {
+            return self.tree.find_path(index);
+        }
+        Ok(self.witness(index)?.path())
+    }
+
+    fn leaves(&self, from: u64) -> Vec<Hash> {
+        let from = (from as usize).min(self.commitments.len());
+        self.commitments[from..].to_vec()
+    }
+
+    fn consistency_proof(
+        &self,
+        old_size: u64,
+        new_size: u64,
+    ) -> Result<PoolConsistencyProof, Error> {
+        if old_size >= new_size || new_size > self.tree.size() {
+            return Err(Error::InvalidRange);
+        }
+        let appended = &self.commitments[old_size as usize..new_size as usize];
+        self.frontier(old_size)?.consistency_proof(appended)
+    }
+
+    fn checkpoint(&mut self, id: u64) {
+        self.checkpoints.insert(id, self.tree.clone());
+    }
+
+    fn rewind_to(&mut self, id: u64) -> Result<(), Error> {
+        let tree = self
+            .checkpoints
+            .get(&id)
+            .cloned()
+            .ok_or(Error::CheckpointNotFound)?;
+        self.checkpoints.split_off(&(id + 1));
+        self.frontiers.split_off(&(tree.size() + 1));
+        self.commitments.truncate(tree.size() as usize);
+        self.tree = tree;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+enum CommitmentTree {
+    Frontier(CommitmentLog),
+    Dense(DenseIncrementalMerkleTree<PoolHasher, TREE_DEPTH>),
+    MountainRange(MerkleMountainRange<PoolHasher>),
+}
+
+impl CommitmentTree {
+    /// Tree of `accumulator`, whose roots are kept in a history of
+    /// `root_history_size`.
+    fn new(accumulator: Accumulator, root_history_size: usize) -> Self {
+        match accumulator {
+            Accumulator::Frontier => Self::Frontier(CommitmentLog::new(root_history_size)),
+            Accumulator::Dense => Self::Dense(DenseIncrementalMerkleTree::new()),
+            Accumulator::MountainRange => Self::MountainRange(MerkleMountainRange::new()),
+        }
+    }
+
+    fn accumulator(&self) -> Accumulator {
+        match self {
+            Self::Frontier(_) => Accumulator::Frontier,
+            Self::Dense(_) => Accumulator::Dense,
+            Self::MountainRange(_) => Accumulator::MountainRange,
+        }
+    }
+
+    fn capacity(&self) -> u64 {
+        match self {
+            Self::Frontier(_) => FrontierMerkleTree::<PoolHasher, TREE_DEPTH>::capacity(),
+            Self::Dense(_) => DenseIncrementalMerkleTree::<PoolHasher, TREE_DEPTH>::capacity(),
+            // Paths must fit the `TREE_DEPTH` levels of the withdrawal
+            // circuit, which holds for up to 2^TREE_DEPTH - 1 leaves.
+            Self::MountainRange(_) => (1 << TREE_DEPTH) - 1,
+        }
+    }
+
+    fn insert_leaf(&mut self, leaf: Hash) -> Result<u64, Error> {
+        if self.size() >= self.capacity() {
+            return Err(Error::TreeFull);
+        }
+        match self {
+            Self::Frontier(log) => log.insert_leaf(leaf),
+            Self::Dense(tree) => tree.insert_leaf(leaf),
+            Self::MountainRange(mmr) => mmr.insert_leaf(leaf),
+        }
+    }
+
+    fn root(&self) -> Result<Hash, Error> {
+        match self {
+            Self::Frontier(log) => log.tree.root(),
+            Self::Dense(tree) => tree.root(),
+            Self::MountainRange(mmr) => mmr.root(),
+        }
+    }
+
+    fn size(&self) -> u64 {
+        match self {
+            Self::Frontier(log) => log.tree.size(),
+            Self::Dense(tree) => tree.size(),
+            Self::MountainRange(mmr) => mmr.size(),
+        }
+    }
+
+    fn find_path(&self, index: u64) -> Result<NotePath, Error> {
+        match self {
+            Self::Frontier(log) => log.find_path(index),
+            Self::Dense(tree) => tree.find_path(index),
+            Self::MountainRange(mmr) => mmr.find_path(index),
+        }
+    }
+
+    fn leaves(&self, from: u64) -> Result<Vec<Hash>, Error> {
+        match self {
+            Self::Frontier(log) => Ok(log.leaves(from)),
+            Self::Dense(tree) => tree.leaves(from),
+            Self::MountainRange(mmr) => mmr.leaves(from),
+        }
+    }
+
+    fn checkpoint(&mut self, id: u64) {
+        match self {
+            Self::Frontier(log) => log.checkpoint(id),
+            Self::Dense(tree) => tree.checkpoint(id),
+            Self::MountainRange(mmr) => mmr.checkpoint(id),
+        }
+    }
+
+    fn rewind_to(&mut self, id: u64) -> Result<(), Error> {
+        match self {
+            Self::Frontier(log) => log.rewind_to(id),
+            Self::Dense(tree) => tree.rewind_to(id),
+            Self::MountainRange(mmr) => mmr.rewind_to(id),
+        }
+    }
+}
+
+/// One tree of the pool and the recent roots of that tree. A new epoch
+/// starts when the current tree is full; earlier epochs keep their roots.
+#[derive(Debug, Clone)]
+struct Epoch {
+    tree: CommitmentTree,
+    root_history: RootHistory,
+}
+
+/// Pool state that is not append-only, saved by `ShieldedPool::checkpoint`.
+/// The tree of the current epoch keeps its own checkpoint under the same id;
+/// earlier epochs are full and never change.
+#[derive(Debug, Clone)]
+struct PoolCheckpoint {
+    epochs: usize,
+    root_history: RootHistory,
+    spent: usize,
+}
+
+/// Shielded side of a pool: its commitment trees, root histories and spent
+/// nullifiers, without a ledger. `PoolManager` runs one per denomination
+/// over a shared ledger.
+pub struct ShieldedPool {
+    epochs: Vec<Epoch>,
+    epoch_capacity: u64,
+    nullifiers: SparseMerkleTree<PoolHasher>,
+    spent: Vec<Hash>,
+    checkpoints: BTreeMap<u64, PoolCheckpoint>,
+    verifying_key: PreparedVerifyingKey<Bn254>,
+    transfer_key: Option<PreparedVerifyingKey<Bn254>>,
+    denomination: u64,
+}
+
+impl ShieldedPool {
+    pub fn new(verifying_key: &WithdrawVerifyingKey) -> Self {
+        Self::with_root_history(verifying_key, DEFAULT_ROOT_HISTORY_SIZE)
+    }
+
+    /// Pool accepting proofs against any of the last `root_history_size` roots.
+    pub fn with_root_history(
+        verifying_key: &WithdrawVerifyingKey,
+        root_history_size: usize,
+    ) -> Self {
+        Self::with_accumulator(verifying_key, Accumulator::default(), root_history_size)
+    }
+
+    /// Pool keeping its commitments in `accumulator`.
+    pub fn with_accumulator(
+        verifying_key: &WithdrawVerifyingKey,
+        accumulator: Accumulator,
+        root_history_size: usize,
+    ) -> Self {
+        let tree = CommitmentTree::new(accumulator, root_history_size);
+        let epoch_capacity = tree.capacity();
+        Self {
+            epochs: vec![Epoch {
+                tree,
+                root_history: RootHistory::new(root_history_size),
+            }],
+            epoch_capacity,
+            nullifiers: SparseMerkleTree::new(),
+            spent: Vec::new(),
+            checkpoints: BTreeMap::new(),
+            verifying_key: prepare_verifying_key(verifying_key),
+            transfer_key: None,
+            denomination: DEFAULT_AMOUNT,
+        }
+    }
+
+    /// Pool whose notes are all worth `denomination` instead of the default
+    /// amount.
+    pub fn with_denomination(mut self, denomination: u64) -> Self {
+        self.denomination = denomination;
+        self
+    }
+
+    /// Accepts shielded transfers proven against `transfer_key`.
+    pub fn with_transfer_key(mut self, transfer_key: &TransferVerifyingKey) -> Self {
+        self.transfer_key = Some(prepare_verifying_key(transfer_key));
+        self
+    }
+
+    /// Starts a new epoch after `capacity` deposits instead of when the tree
+    /// is full. Capped at the capacity of the tree.
+    pub fn with_epoch_capacity(mut self, capacity: u64) -> Self {
+        self.epoch_capacity = capacity.clamp(1, self.current().tree.capacity());
+        self
+    }
+
+    pub fn accumulator(&self) -> Accumulator {
+        self.current().tree.accumulator()
+    }
+
+    /// Epoch receiving new deposits.
+    pub fn current_epoch(&self) -> u64 {
+        self.epochs.len() as u64 - 1
+    }
+
+    fn current(&self) -> &Epoch {
+        &self.epochs[self.epochs.len() - 1]
+    }
+
+    fn epoch(&self, epoch: u64) -> Result<&Epoch, Error> {
+        self.epochs.get(epoch as usize).ok_or(Error::UnknownEpoch)
+    }
+
+    /// Amount of every note of the pool, moved by each deposit and
+    /// withdrawal.
+    pub fn denomination(&self) -> u64 {
+        self.denomination
+    }
+
+    /// Nullifiers of all spent notes.
+    pub fn nullifiers(&self) -> HashSet<Hash> {
+        self.nullifiers.keys().cloned().collect()
+    }
+
+    /// Root of the spent nullifier set.
+    pub fn nullifier_root(&self) -> Hash {
+        self.nullifiers.root()
+    }
+
+    /// Proof against `nullifier_root` that `nullifier_hash` has or has not
+    /// been spent.
+    pub fn nullifier_path(&self, nullifier_hash: &Hash) -> NullifierPath {
+        self.nullifiers.find_path(nullifier_hash)
+    }
+
+    /// Whether `root` is one of the recent roots of `epoch` a withdrawal may
+    /// prove against.
+    pub fn is_known_root(&self, epoch: u64, root: &Hash) -> bool {
+        self.epoch(epoch)
+            .is_ok_and(|epoch| epoch.root_history.contains(root))
+    }
+
+    /// Root of the current epoch after its most recent deposit, if any.
+    pub fn latest_root(&self) -> Option<Hash> {
+        self.current().root_history.latest().cloned()
+    }
+
+    /// Tree side of a deposit: appends `commitment` to the current epoch,
+    /// starting a new one if it is full, and records the new root. A
+    /// commitment no proof could open, for not being a canonical field
+    /// element, is rejected.
+    pub(crate) fn insert_commitment(&mut self, commitment: Hash) -> Result<(u64, u64), Error> {
+        let commitment = canonical(&commitment)?;
+        if self.current().tree.size() >= self.epoch_capacity {
+            let current = self.current();
+            let epoch = Epoch {
+                tree: CommitmentTree::new(current.tree.accumulator(), current.root_history.size()),
+                root_history: RootHistory::new(current.root_history.size()),
+            };
+            self.epochs.push(epoch);
+        }
+        let epoch = self.current_epoch();
+        let current = &mut self.epochs[epoch as usize];
+        let index = current.tree.insert_leaf(commitment)?;
+
+        let root = current.tree.root()?;
+        current.root_history.push(root, current.tree.size());
+
+        Ok((epoch, index))
+    }
+
+    /// Path of a commitment against the latest root of its epoch.
+    pub fn find_path(&self, epoch: u64, index: u64) -> Result<NotePath, Error> {
+        self.epoch(epoch)?.tree.find_path(index)
+    }
+
+    /// Witness of a commitment, to be kept up to date with `commitments` as
+    /// later deposits arrive. Mountain range pools have no witnesses; their
+    /// wallets ask for a fresh `find_path` instead.
+    pub fn witness(&self, epoch: u64, index: u64) -> Result<NoteWitness, Error> {
+        match &self.epoch(epoch)?.tree {
+            CommitmentTree::Frontier(log) => log.witness(index),
+            CommitmentTree::Dense(tree) => tree.witness(index),
+            CommitmentTree::MountainRange(_) => Err(Error::Unsupported),
+        }
+    }
+
+    /// Commitments of `epoch` from leaf index `from` onwards, in insertion
+    /// order.
+    pub fn commitments(&self, epoch: u64, from: u64) -> Result<Vec<Hash>, Error> {
+        self.epoch(epoch)?.tree.leaves(from)
+    }
+
+    /// Proves that `new_root` extends `old_root`, both taken from the root
+    /// history of `epoch`. Not supported by mountain range pools.
+    pub fn consistency_proof(
+        &self,
+        epoch: u64,
+        old_root: &Hash,
+        new_root: &Hash,
+    ) -> Result<PoolConsistencyProof, Error> {
+        let epoch = self.epoch(epoch)?;
+        let old_size = epoch
+            .root_history
+            .leaves(old_root)
+            .ok_or(Error::UnknownRoot)?;
+        let new_size = epoch
+            .root_history
+            .leaves(new_root)
+            .ok_or(Error::UnknownRoot)?;
+        match &epoch.tree {
+            CommitmentTree::Frontier(log) => log.consistency_proof(old_size, new_size),
+            CommitmentTree::Dense(tree) => tree.consistency_proof(old_size, new_size),
+            CommitmentTree::MountainRange(_) => Err(Error::Unsupported),
+        }
+    }
+
+    /// Proof side of a withdrawal: checks the fee and the proof against the
+    /// root history of its epoch, and records the nullifier.
+    pub(crate) fn spend(
+        &mut self,
+        withdrawal: &Withdrawal,
+        proof: &WithdrawProof,
+    ) -> Result<(), Error> {
+        if withdrawal.payout.fee > self.denomination {
+            return Err(Error::FeeTooHigh);
+        }
+        let nullifier_hash = canonical(&withdrawal.nullifier_hash)?;
+        let root = canonical(&withdrawal.root)?;
+        if self.nullifiers.contains(&nullifier_hash) {
+            return Err(Error::NullifierSpent);
+        }
+        if !self.is_known_root(withdrawal.epoch, &root) {
+            return Err(Error::UnknownRoot);
+        }
+        if !zk::verify(&self.verifying_key, self.denomination, withdrawal, proof) {
+            return Err(Error::InvalidProof);
+        }
+
+        self.nullifiers.insert(nullifier_hash.clone());
+        self.spent.push(nullifier_hash);
+
+        Ok(())
+    }
+
+    /// Spends up to `TRANSFER_INPUTS` notes into up to `TRANSFER_OUTPUTS`
+    /// new ones of the same asset and total amount, without touching the
+    /// ledger. Returns the epoch and leaf index of each new commitment.
+    ///
+    /// Withdrawals still pay exactly the denomination, so a note split by a
+    /// transfer leaves the pool once merged back into a whole one.
+    pub fn transfer(
+        &mut self,
+        transfer: &Transfer,
+        proof: &TransferProof,
+    ) -> Result<Vec<(u64, u64)>, Error> {
+        let transfer_key = self.transfer_key.as_ref().ok_or(Error::Unsupported)?;
+        let nullifiers = transfer
+            .nullifiers
+            .iter()
+            .map(canonical)
+            .collect::<Result<Vec<_>, _>>()?;
+        let commitments = transfer
+            .commitments
+            .iter()
+            .map(canonical)
+            .collect::<Result<Vec<_>, _>>()?;
+        let root = canonical(&transfer.root)?;
+        let unique: HashSet<&Hash> = nullifiers.iter().collect();
+        // Zero hashes stand for unused slots, and a note spent twice in the
+        // same transfer would count twice.
+        if !(1..=TRANSFER_INPUTS).contains(&nullifiers.len())
+            || !(1..=TRANSFER_OUTPUTS).contains(&commitments.len())
+            || unique.len() != nullifiers.len()
+            || unique.contains(&Hash::default())
+            || commitments.contains(&Hash::default())
+        {
+            return Err(Error::InvalidTransfer);
+        }
+        if nullifiers
+            .iter()
+            .any(|nullifier| self.nullifiers.contains(nullifier))
+        {
+            return Err(Error::NullifierSpent);
+        }
+        if !self.is_known_root(transfer.epoch, &root) {
+            return Err(Error::UnknownRoot);
+        }
+        if !zk::verify_transfer(transfer_key, transfer, proof) {
+            return Err(Error::InvalidProof);
+        }
+
+        // Outputs go in before the inputs are marked spent, so a failed
+        // insertion cannot burn the inputs.
+        let positions = commitments
+            .into_iter()
+            .map(|commitment| self.insert_commitment(commitment))
+            .collect::<Result<Vec<_>, _>>()?;
+        for nullifier in nullifiers {
+            self.nullifiers.insert(nullifier.clone());
+            self.spent.push(nullifier);
+        }
+        Ok(positions)
     }
+
+    /// Saves the pool state under `id`, e.g. the height of the block it
+    /// follows. Ids are expected to increase; an existing id is overwritten.
+    pub fn checkpoint(&mut self, id: u64) {
+        let epochs = self.epochs.len();
+        self.epochs[epochs - 1].tree.checkpoint(id);
+        self.checkpoints.insert(
+            id,
+            PoolCheckpoint {
+                epochs,
+                root_history: self.current().root_history.clone(),
+                spent: self.spent.len(),
+            },
+        );
+    }
+
+    /// Undoes every deposit and spend made after checkpoint `id`: their
+    /// commitments, roots, epochs and nullifiers. Later checkpoints are
+    /// dropped.
+    pub fn rewind_to(&mut self, id: u64) -> Result<(), Error> {
+        let checkpoint = self
+            .checkpoints
+            .get(&id)
+            .cloned()
+            .ok_or(Error::CheckpointNotFound)?;
+        self.epochs.truncate(checkpoint.epochs);
+        let current = &mut self.epochs[checkpoint.epochs - 1];
+        current.tree.rewind_to(id)?;
+        current.root_history = checkpoint.root_history;
+        self.checkpoints.split_off(&(id + 1));
+
+        for nullifier in self.spent.drain(checkpoint.spent..) {
+            self.nullifiers.remove(&nullifier);
+        }
+        Ok(())
+    }
+}
+
+/// A `ShieldedPool` with its own ledger, seeded with funds for the default
+/// account.
+pub struct AnonymityPool {
+    pool: ShieldedPool,
+    balances: Balances,
+    checkpoints: BTreeMap<u64, Balances>,
 }
 
 impl AnonymityPool {
-    pub fn new() -> Self {
-        let tree = DenseIncrementalMerkleTree::<Keccak256>::new();
+    pub fn new(verifying_key: &WithdrawVerifyingKey) -> Self {
+        Self::with_root_history(verifying_key, DEFAULT_ROOT_HISTORY_SIZE)
+    }
+
+    /// Pool accepting proofs against any of the last `root_history_size` roots.
+    pub fn with_root_history(
+        verifying_key: &WithdrawVerifyingKey,
+        root_history_size: usize,
+    ) -> Self {
+        Self::with_accumulator(verifying_key, Accumulator::default(), root_history_size)
+    }
+
+    /// Pool keeping its commitments in `accumulator`.
+    pub fn with_accumulator(
+        verifying_key: &WithdrawVerifyingKey,
+        accumulator: Accumulator,
+        root_history_size: usize,
+    ) -> Self {
         let mut balances = HashMap::new();
-        balances.insert(DEFAULT_ACCOUNT, DEFAULT_AMOUNT * 10);
+        balances.insert((DEFAULT_ACCOUNT, NATIVE_ASSET), DEFAULT_AMOUNT * 10);
         Self {
-            tree,
-            nullifiers: HashMap::new(),
+            pool: ShieldedPool::with_accumulator(verifying_key, accumulator, root_history_size),
             balances,
-            root_history: Vec::new(),
+            checkpoints: BTreeMap::new(),
         }
     }
 
+    /// See `ShieldedPool::with_denomination`.
+    pub fn with_denomination(mut self, denomination: u64) -> Self {
+        self.pool = self.pool.with_denomination(denomination);
+        self
+    }
+
+    /// See `ShieldedPool::with_transfer_key`.
+    pub fn with_transfer_key(mut self, transfer_key: &TransferVerifyingKey) -> Self {
+        self.pool = self.pool.with_transfer_key(transfer_key);
+        self
+    }
+
+    /// See `ShieldedPool::with_epoch_capacity`.
+    pub fn with_epoch_capacity(mut self, capacity: u64) -> Self {
+        self.pool = self.pool.with_epoch_capacity(capacity);
+        self
+    }
+
     pub fn account() -> u64 {
         DEFAULT_ACCOUNT
     }
 
-    pub fn amount() -> u64 {
-        DEFAULT_AMOUNT
+    /// Amount moved by each deposit and withdrawal: the denomination.
+    pub fn amount(&self) -> u64 {
+        self.pool.denomination()
     }
 
-    pub fn nullifiers(&self) -> HashMap<Hash, bool> {
-        self.nullifiers.clone()
+    pub fn accumulator(&self) -> Accumulator {
+        self.pool.accumulator()
     }
 
-    pub fn balances(&self) -> HashMap<u64, u64> {
+    /// Epoch receiving new deposits.
+    pub fn current_epoch(&self) -> u64 {
+        self.pool.current_epoch()
+    }
+
+    pub fn denomination(&self) -> u64 {
+        self.pool.denomination()
+    }
+
+    /// Nullifiers of all spent notes.
+    pub fn nullifiers(&self) -> HashSet<Hash> {
+        self.pool.nullifiers()
+    }
+
+    /// Root of the spent nullifier set.
+    pub fn nullifier_root(&self) -> Hash {
+        self.pool.nullifier_root()
+    }
+
+    pub fn nullifier_path(&self, nullifier_hash: &Hash) -> NullifierPath {
+        self.pool.nullifier_path(nullifier_hash)
+    }
+
+    pub fn balances(&self) -> Balances {
         self.balances.clone()
     }
 
-    pub fn get_balance(&self, account: u64) -> u64 {
-        *self.balances.get(&account).unwrap_or(&0)
+    pub fn get_balance(&self, account: u64, asset: u64) -> u64 {
+        *self.balances.get(&(account, asset)).unwrap_or(&0)
+    }
+
+    /// Credits `amount` of `asset` to `account`, e.g. when it is bridged in.
+    pub fn mint(&mut self, account: u64, asset: u64, amount: u64) {
+        *self.balances.entry((account, asset)).or_insert(0) += amount;
+    }
+
+    pub fn is_known_root(&self, epoch: u64, root: &Hash) -> bool {
+        self.pool.is_known_root(epoch, root)
+    }
+
+    pub fn latest_root(&self) -> Option<Hash> {
+        self.pool.latest_root()
+    }
+
+    /// Inserts a commitment built by the wallet and moves the deposit amount
+    /// of `asset` from `sender` to the pool. Returns the epoch and the leaf
+    /// index of the commitment, starting a new epoch if the current one is
+    /// full.
+    pub fn deposit_commitment(
+        &mut self,
+        sender: u64,
+        asset: u64,
+        commitment: Hash,
+    ) -> Result<(u64, u64), Error> {
+        if self.get_balance(sender, asset) < self.amount() {
+            return Err(Error::InsufficientBalance);
+        }
+        let position = self.pool.insert_commitment(commitment)?;
+        pay_in(&mut self.balances, sender, asset, self.pool.denomination());
+        Ok(position)
     }
 
-    pub fn deposit(&mut self, sender: u64, secret: u64, topic: u64, recipiant: u64) -> Note {
-        let secret_hash = hash_leaf::<Keccak256>(secret.to_be_bytes().to_vec());
-        let topic_hash = hash_leaf::<Keccak256>(topic.to_be_bytes().to_vec());
+    pub fn find_path(&self, epoch: u64, index: u64) -> Result<NotePath, Error> {
+        self.pool.find_path(epoch, index)
+    }
 
-        let nullifier = hash_two::<Keccak256>(secret_hash.clone(), topic_hash);
-        let commitment = hash_two::<Keccak256>(secret_hash.clone(), secret_hash);
+    pub fn witness(&self, epoch: u64, index: u64) -> Result<NoteWitness, Error> {
+        self.pool.witness(epoch, index)
+    }
 
-        assert!(*self.balances.get(&sender).unwrap_or(&0) > DEFAULT_AMOUNT);
+    pub fn commitments(&self, epoch: u64, from: u64) -> Result<Vec<Hash>, Error> {
+        self.pool.commitments(epoch, from)
+    }
 
-        let index = self.tree.insert_leaf(commitment);
-        self.nullifiers.insert(nullifier, false);
+    pub fn consistency_proof(
+        &self,
+        epoch: u64,
+        old_root: &Hash,
+        new_root: &Hash,
+    ) -> Result<PoolConsistencyProof, Error> {
+        self.pool.consistency_proof(epoch, old_root, new_root)
+    }
 
-        let root = self.tree.root().unwrap();
-        self.root_history.push(root);
+    /// Spends a note given only its public data. The pool never learns
+    /// which commitment of the epoch the proof refers to.
+    pub fn withdraw(
+        &mut self,
+        withdrawal: &Withdrawal,
+        proof: &WithdrawProof,
+    ) -> Result<(), Error> {
+        self.pool.spend(withdrawal, proof)?;
+        pay_withdrawal(&mut self.balances, withdrawal, self.pool.denomination());
+        Ok(())
+    }
 
-        // Deposit amount to contract
-        self.balances
-            .entry(sender)
-            .and_modify(|x| *x -= DEFAULT_AMOUNT);
-        self.balances
-            .entry(CONTRACT_ADDRESS)
-            .and_modify(|x| *x += DEFAULT_AMOUNT);
+    /// See `ShieldedPool::transfer`.
+    pub fn transfer(
+        &mut self,
+        transfer: &Transfer,
+        proof: &TransferProof,
+    ) -> Result<Vec<(u64, u64)>, Error> {
+        self.pool.transfer(transfer, proof)
+    }
 
-        let merkle_path = self.tree.find_path(index);
+    /// Saves the pool state and the ledger under `id`, e.g. the height of
+    /// the block it follows.
+    pub fn checkpoint(&mut self, id: u64) {
+        self.pool.checkpoint(id);
+        self.checkpoints.insert(id, self.balances.clone());
+    }
 
-        Note {
-            secret,
-            topic,
-            recipiant,
-            merkle_path,
+    /// Undoes every deposit and withdrawal made after checkpoint `id`,
+    /// including their balance changes. Later checkpoints are dropped.
+    pub fn rewind_to(&mut self, id: u64) -> Result<(), Error> {
+        let balances = self
+            .checkpoints
+            .get(&id)
+            .cloned()
+            .ok_or(Error::CheckpointNotFound)?;
+        self.pool.rewind_to(id)?;
+        self.checkpoints.split_off(&(id + 1));
+        self.balances = balances;
+        Ok(())
+    }
+}
+
+/// Canonical encoding of a public input of a proof. The verifier reduces its
+/// inputs mod p, so an alias `x + p` would otherwise pass as a new nullifier
+/// or an unknown root.
+fn canonical(hash: &Hash) -> Result<Hash, Error> {
+    Ok(Field::from_hash(hash)?.to_hash())
+}
+
+/// Moves `amount` of `asset` from `sender` to the pool contract. The
+/// sender's balance is checked by the caller.
+pub(crate) fn pay_in(balances: &mut Balances, sender: u64, asset: u64, amount: u64) {
+    balances.entry((sender, asset)).and_modify(|x| *x -= amount);
+    balances
+        .entry((CONTRACT_ADDRESS, asset))
+        .and_modify(|x| *x += amount);
+}
+
+/// Moves `amount` of `asset` from the pool contract to `recipient`.
+pub(crate) fn pay_out(balances: &mut Balances, recipient: u64, asset: u64, amount: u64) {
+    balances
+        .entry((CONTRACT_ADDRESS, asset))
+        .and_modify(|x| *x -= amount);
+    *balances.entry((recipient, asset)).or_insert(0) += amount;
+}
+
+/// Pays out a spent note of `amount` units: the fee to the relayer and the
+/// rest to the recipient. The fee is checked by `spend`.
+pub(crate) fn pay_withdrawal(balances: &mut Balances, withdrawal: &Withdrawal, amount: u64) {
+    let Payout {
+        recipient,
+        relayer,
+        fee,
+    } = withdrawal.payout;
+    pay_out(balances, recipient, withdrawal.asset, amount - fee);
+    if fee > 0 {
+        pay_out(balances, relayer, withdrawal.asset, fee);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        Accumulator, AnonymityPool, Note, Payout, RootHistory, SpendingKey, Transfer, Withdrawal,
+        NATIVE_ASSET, TREE_DEPTH,
+    };
+    use crate::{note::prove_transfer, poseidon::hash_to_field, zk, Error, Field, Hash};
+    use ark_bn254::Fr;
+    use ark_ff::{BigInteger, PrimeField};
+    use rand::thread_rng;
+
+    /// Non-canonical encoding `x + p` of the same field element as `hash`.
+    fn alias(hash: &Hash) -> Hash {
+        let mut value = hash_to_field(hash).into_bigint();
+        value.add_with_carry(&Fr::MODULUS);
+        Hash::from_hex(hex::encode(value.to_bytes_be())).unwrap()
+    }
+
+    fn withdrawal(epoch: u64, root: Hash, nullifier_hash: Hash, recipient: u64) -> Withdrawal {
+        Withdrawal {
+            asset: NATIVE_ASSET,
+            epoch,
+            root,
+            nullifier_hash,
+            payout: Payout::new(recipient),
         }
     }
 
-    pub fn withdraw(&mut self, note: Note) -> bool {
-        let secret_hash = hash_leaf::<Keccak256>(note.secret.to_be_bytes().to_vec());
-        let topic_hash = hash_leaf::<Keccak256>(note.topic.to_be_bytes().to_vec());
-        let nullifier = hash_two::<Keccak256>(secret_hash.clone(), topic_hash);
+    #[test]
+    fn should_withdraw_with_proof() {
+        let rng = &mut thread_rng();
+        let (proving_key, verifying_key) = zk::setup(rng).unwrap();
+        let mut pool = AnonymityPool::new(&verifying_key);
+
+        let spending_key = SpendingKey::random(rng);
+        let note = Note::random(rng, pool.amount(), NATIVE_ASSET, spending_key.public_key());
+        let (epoch, index) = pool
+            .deposit_commitment(AnonymityPool::account(), NATIVE_ASSET, note.commitment())
+            .unwrap();
+        let mut witness = pool.witness(epoch, index).unwrap();
+        let other = Note::random(rng, pool.amount(), NATIVE_ASSET, spending_key.public_key());
+        pool.deposit_commitment(AnonymityPool::account(), NATIVE_ASSET, other.commitment())
+            .unwrap();
+        for commitment in pool.commitments(epoch, witness.size()).unwrap() {
+            witness.append(commitment).unwrap();
+        }
+        let path = witness.path();
+        assert_eq!(pool.latest_root(), Some(path.construct_root()));
+        // Nothing is recorded about the note until it is spent.
+        assert!(pool.nullifiers().is_empty());
+        let unspent_root = pool.nullifier_root();
+        assert!(pool
+            .nullifier_path(&note.nullifier())
+            .verify_non_membership(&unspent_root)
+            .is_ok());
+
+        let root = path.construct_root();
+        let proof = note
+            .prove(&spending_key, &path, &Payout::new(456), &proving_key, rng)
+            .unwrap();
+
+        // Proof is bound to the recipient.
+        assert_eq!(
+            pool.withdraw(
+                &withdrawal(epoch, root.clone(), note.nullifier(), 789),
+                &proof
+            ),
+            Err(Error::InvalidProof)
+        );
+        assert_eq!(
+            pool.withdraw(
+                &withdrawal(epoch, Field::random(rng).to_hash(), note.nullifier(), 456),
+                &proof
+            ),
+            Err(Error::UnknownRoot)
+        );
+        assert!(pool
+            .withdraw(
+                &withdrawal(epoch, root.clone(), note.nullifier(), 456),
+                &proof
+            )
+            .is_ok());
+        assert!(pool.nullifiers().contains(&note.nullifier()));
+        assert_ne!(pool.nullifier_root(), unspent_root);
+        assert!(pool
+            .nullifier_path(&note.nullifier())
+            .verify_membership(&pool.nullifier_root())
+            .is_ok());
+        // Nullifier can only be spent once.
+        assert_eq!(
+            pool.withdraw(
+                &withdrawal(epoch, root.clone(), note.nullifier(), 456),
+                &proof
+            ),
+            Err(Error::NullifierSpent)
+        );
+        // Also not under an alias the verifier reduces to the same element.
+        assert_eq!(
+            pool.withdraw(
+                &withdrawal(epoch, root.clone(), alias(&note.nullifier()), 456),
+                &proof
+            ),
+            Err(Error::NonCanonicalField)
+        );
+        assert_eq!(
+            pool.withdraw(
+                &withdrawal(epoch, alias(&root), note.nullifier(), 456),
+                &proof
+            ),
+            Err(Error::NonCanonicalField)
+        );
+    }
 
-        if let Some(&is_nullifier_taken) = self.nullifiers.get(&nullifier) {
-            if is_nullifier_taken {
-                return false;
+    #[test]
+    fn should_reject_deposit_without_balance() {
+        let rng = &mut thread_rng();
+        let (_, verifying_key) = zk::setup(rng).unwrap();
+        let mut pool = AnonymityPool::new(&verifying_key);
+
+        let pk = SpendingKey::random(rng).public_key();
+        let note = Note::random(rng, pool.amount(), NATIVE_ASSET, pk);
+        assert_eq!(
+            pool.deposit_commitment(456, NATIVE_ASSET, note.commitment()),
+            Err(Error::InsufficientBalance)
+        );
+
+        // Nor can a commitment no proof could open be deposited.
+        assert_eq!(
+            pool.deposit_commitment(
+                AnonymityPool::account(),
+                NATIVE_ASSET,
+                alias(&note.commitment())
+            ),
+            Err(Error::NonCanonicalField)
+        );
+        assert_eq!(pool.latest_root(), None);
+        assert_eq!(
+            pool.get_balance(AnonymityPool::account(), NATIVE_ASSET),
+            pool.amount() * 10
+        );
+
+        // Deposits move the configured denomination.
+        let mut pool = AnonymityPool::new(&verifying_key).with_denomination(250);
+        assert_eq!(pool.amount(), 250);
+        pool.mint(456, NATIVE_ASSET, 300);
+        let note = Note::random(rng, pool.amount(), NATIVE_ASSET, pk);
+        assert!(pool
+            .deposit_commitment(456, NATIVE_ASSET, note.commitment())
+            .is_ok());
+        assert_eq!(pool.get_balance(456, NATIVE_ASSET), 50);
+        assert_eq!(
+            pool.deposit_commitment(456, NATIVE_ASSET, note.commitment()),
+            Err(Error::InsufficientBalance)
+        );
+    }
+
+    #[test]
+    fn root_history_should_evict_oldest_roots() {
+        let rng = &mut thread_rng();
+        let roots: Vec<Hash> = (0..5).map(|_| Hash::random(rng)).collect();
+        let mut history = RootHistory::new(3);
+        assert_eq!(history.latest(), None);
+
+        for (leaves, root) in roots.iter().enumerate() {
+            history.push(root.clone(), leaves as u64);
+            assert_eq!(history.latest(), Some(root));
+        }
+        assert_eq!(history.len(), 3);
+        assert!(!history.contains(&roots[0]));
+        assert!(!history.contains(&roots[1]));
+        assert!(roots[2..].iter().all(|root| history.contains(root)));
+        assert_eq!(history.leaves(&roots[3]), Some(3));
+        assert_eq!(history.leaves(&roots[0]), None);
+
+        // A repeated root stays known until its last occurrence is evicted.
+        let mut history = RootHistory::new(3);
+        history.push(roots[0].clone(), 0);
+        history.push(roots[1].clone(), 1);
+        history.push(roots[0].clone(), 0);
+        history.push(roots[2].clone(), 2);
+        assert!(history.contains(&roots[0]));
+        history.push(roots[3].clone(), 3);
+        history.push(roots[4].clone(), 4);
+        assert!(!history.contains(&roots[0]));
+    }
+
+    #[test]
+    fn pool_should_forget_old_roots() {
+        let rng = &mut thread_rng();
+        let (_, verifying_key) = zk::setup(rng).unwrap();
+        let mut pool = AnonymityPool::with_root_history(&verifying_key, 2);
+        assert_eq!(pool.latest_root(), None);
+
+        let pk = SpendingKey::random(rng).public_key();
+        let mut roots = Vec::new();
+        for _ in 0..3 {
+            let note = Note::random(rng, pool.amount(), NATIVE_ASSET, pk);
+            pool.deposit_commitment(AnonymityPool::account(), NATIVE_ASSET, note.commitment())
+                .unwrap();
+            roots.push(pool.latest_root().unwrap());
+        }
+        assert!(!pool.is_known_root(0, &roots[0]));
+        assert!(pool.is_known_root(0, &roots[1]));
+        assert!(pool.is_known_root(0, &roots[2]));
+        assert!(!pool.is_known_root(1, &roots[2]));
+
+        let proof = pool.consistency_proof(0, &roots[1], &roots[2]).unwrap();
+        assert!(proof.verify(&roots[1], &roots[2]).is_ok());
+        assert_eq!(
+            pool.consistency_proof(0, &roots[0], &roots[2]).unwrap_err(),
+            Error::UnknownRoot
+        );
+        assert_eq!(
+            pool.consistency_proof(0, &roots[2], &roots[1]).unwrap_err(),
+            Error::InvalidRange
+        );
+    }
+
+    #[test]
+    fn frontier_pool_should_match_dense_pool() {
+        let rng = &mut thread_rng();
+        let (_, verifying_key) = zk::setup(rng).unwrap();
+        let mut frontier = AnonymityPool::new(&verifying_key);
+        let mut dense = AnonymityPool::with_accumulator(&verifying_key, Accumulator::Dense, 100);
+        // Keeps the frontiers of only its two latest sizes.
+        let mut short = AnonymityPool::with_root_history(&verifying_key, 2);
+        assert_eq!(frontier.accumulator(), Accumulator::Frontier);
+
+        let pk = SpendingKey::random(rng).public_key();
+        let mut roots = Vec::new();
+        for i in 0..5 {
+            if i == 3 {
+                frontier.checkpoint(1);
+                dense.checkpoint(1);
             }
+            let note = Note::random(rng, frontier.amount(), NATIVE_ASSET, pk);
+            for pool in [&mut frontier, &mut dense, &mut short] {
+                pool.deposit_commitment(AnonymityPool::account(), NATIVE_ASSET, note.commitment())
+                    .unwrap();
+            }
+            roots.push(frontier.latest_root().unwrap());
+            assert_eq!(dense.latest_root().as_ref(), roots.last());
+        }
+
+        // Paths of earlier commitments are rebuilt from the log.
+        for index in 0..5 {
+            let path = frontier.find_path(0, index).unwrap();
+            assert_eq!(
+                path.neighbours(),
+                dense.find_path(0, index).unwrap().neighbours()
+            );
+            assert_eq!(
+                path.neighbours(),
+                short.find_path(0, index).unwrap().neighbours()
+            );
+            assert_eq!(path.construct_root(), roots[4]);
+            assert_eq!(frontier.witness(0, index).unwrap().root(), roots[4]);
         }
-        let root = note.merkle_path.construct_root();
-        if !self.root_history.contains(&root) {
-            return false;
+        let proof = frontier.consistency_proof(0, &roots[1], &roots[4]).unwrap();
+        assert!(proof.verify(&roots[1], &roots[4]).is_ok());
+        let expected = dense.consistency_proof(0, &roots[1], &roots[4]).unwrap();
+        assert_eq!(proof.neighbours(), expected.neighbours());
+
+        frontier.rewind_to(1).unwrap();
+        dense.rewind_to(1).unwrap();
+        assert_eq!(frontier.latest_root(), Some(roots[2].clone()));
+        assert_eq!(dense.latest_root(), Some(roots[2].clone()));
+        assert_eq!(frontier.commitments(0, 0).unwrap().len(), 3);
+        let path = frontier.find_path(0, 2).unwrap();
+        assert_eq!(path.construct_root(), roots[2]);
+    }
+
+    #[test]
+    fn should_rewind_to_checkpoint() {
+        let rng = &mut thread_rng();
+        let (proving_key, verifying_key) = zk::setup(rng).unwrap();
+        let mut pool = AnonymityPool::new(&verifying_key);
+        let spending_key = SpendingKey::random(rng);
+
+        let note = Note::random(rng, pool.amount(), NATIVE_ASSET, spending_key.public_key());
+        let (epoch, index) = pool
+            .deposit_commitment(AnonymityPool::account(), NATIVE_ASSET, note.commitment())
+            .unwrap();
+        pool.checkpoint(1);
+        let root = pool.latest_root().unwrap();
+        let nullifier_root = pool.nullifier_root();
+        let balances = pool.balances();
+
+        let path = pool.find_path(epoch, index).unwrap();
+        let proof = note
+            .prove(&spending_key, &path, &Payout::new(456), &proving_key, rng)
+            .unwrap();
+        pool.withdraw(
+            &withdrawal(epoch, root.clone(), note.nullifier(), 456),
+            &proof,
+        )
+        .unwrap();
+        let other = Note::random(rng, pool.amount(), NATIVE_ASSET, spending_key.public_key());
+        pool.deposit_commitment(AnonymityPool::account(), NATIVE_ASSET, other.commitment())
+            .unwrap();
+        let reorged_root = pool.latest_root().unwrap();
+        pool.checkpoint(2);
+
+        pool.rewind_to(1).unwrap();
+        assert_eq!(pool.latest_root(), Some(root.clone()));
+        assert!(!pool.is_known_root(epoch, &reorged_root));
+        assert_eq!(pool.nullifier_root(), nullifier_root);
+        assert!(pool.nullifiers().is_empty());
+        assert_eq!(pool.balances(), balances);
+        assert_eq!(pool.commitments(epoch, 0).unwrap(), vec![note.commitment()]);
+        assert_eq!(pool.rewind_to(2), Err(Error::CheckpointNotFound));
+
+        // The rewound withdrawal can be replayed.
+        assert!(pool
+            .withdraw(&withdrawal(epoch, root, note.nullifier(), 456), &proof)
+            .is_ok());
+    }
+
+    #[test]
+    fn should_withdraw_from_mountain_range_pool() {
+        let rng = &mut thread_rng();
+        let (proving_key, verifying_key) = zk::setup(rng).unwrap();
+        let mut pool =
+            AnonymityPool::with_accumulator(&verifying_key, Accumulator::MountainRange, 100);
+        assert_eq!(pool.accumulator(), Accumulator::MountainRange);
+
+        let spending_key = SpendingKey::random(rng);
+        let notes: Vec<Note> = (0..3)
+            .map(|_| Note::random(rng, pool.amount(), NATIVE_ASSET, spending_key.public_key()))
+            .collect();
+        for note in &notes {
+            pool.deposit_commitment(AnonymityPool::account(), NATIVE_ASSET, note.commitment())
+                .unwrap();
         }
+        assert_eq!(pool.witness(0, 0).unwrap_err(), Error::Unsupported);
+
+        let path = pool.find_path(0, 1).unwrap();
+        assert!(path.neighbours().len() < TREE_DEPTH);
+        let root = path.construct_root();
+        assert_eq!(pool.latest_root(), Some(root.clone()));
+
+        let note = &notes[1];
+        let proof = note
+            .prove(&spending_key, &path, &Payout::new(456), &proving_key, rng)
+            .unwrap();
+        assert!(pool
+            .withdraw(&withdrawal(0, root.clone(), note.nullifier(), 456), &proof)
+            .is_ok());
+        assert_eq!(
+            pool.withdraw(&withdrawal(0, root, note.nullifier(), 456), &proof),
+            Err(Error::NullifierSpent)
+        );
+    }
+
+    #[test]
+    fn should_roll_over_to_new_epoch() {
+        let rng = &mut thread_rng();
+        let (proving_key, verifying_key) = zk::setup(rng).unwrap();
+        let mut pool = AnonymityPool::new(&verifying_key).with_epoch_capacity(2);
+        let spending_key = SpendingKey::random(rng);
+
+        let mut notes = Vec::new();
+        for _ in 0..2 {
+            let mut note =
+                Note::random(rng, pool.amount(), NATIVE_ASSET, spending_key.public_key());
+            let (epoch, _) = pool
+                .deposit_commitment(AnonymityPool::account(), NATIVE_ASSET, note.commitment())
+                .unwrap();
+            note.set_epoch(epoch);
+            notes.push(note);
+        }
+        let full_root = pool.latest_root().unwrap();
+        pool.checkpoint(1);
+
+        let other = Note::random(rng, pool.amount(), NATIVE_ASSET, spending_key.public_key());
+        assert_eq!(
+            pool.deposit_commitment(AnonymityPool::account(), NATIVE_ASSET, other.commitment()),
+            Ok((1, 0))
+        );
+        assert_eq!(pool.current_epoch(), 1);
+        assert_ne!(pool.latest_root(), Some(full_root.clone()));
+        assert_eq!(pool.find_path(2, 0).unwrap_err(), Error::UnknownEpoch);
+
+        // Notes of the full epoch are still spent against its last root.
+        let note = &notes[1];
+        assert_eq!(note.epoch(), 0);
+        let path = pool.find_path(note.epoch(), 1).unwrap();
+        assert_eq!(path.construct_root(), full_root);
+        let proof = note
+            .prove(&spending_key, &path, &Payout::new(456), &proving_key, rng)
+            .unwrap();
+        assert_eq!(
+            pool.withdraw(
+                &withdrawal(1, full_root.clone(), note.nullifier(), 456),
+                &proof
+            ),
+            Err(Error::UnknownRoot)
+        );
+        assert!(pool
+            .withdraw(
+                &withdrawal(note.epoch(), full_root, note.nullifier(), 456),
+                &proof
+            )
+            .is_ok());
+
+        pool.rewind_to(1).unwrap();
+        assert_eq!(pool.current_epoch(), 0);
+        assert!(pool.nullifiers().is_empty());
+        assert_eq!(
+            pool.deposit_commitment(AnonymityPool::account(), NATIVE_ASSET, other.commitment()),
+            Ok((1, 0))
+        );
+    }
+
+    #[test]
+    fn should_keep_assets_apart() {
+        let rng = &mut thread_rng();
+        let (proving_key, verifying_key) = zk::setup(rng).unwrap();
+        let mut pool = AnonymityPool::new(&verifying_key);
+        let account = AnonymityPool::account();
+        let stablecoin = 7;
+        pool.mint(account, stablecoin, pool.amount());
+
+        let spending_key = SpendingKey::random(rng);
+        let note = Note::random(rng, pool.amount(), stablecoin, spending_key.public_key());
+        assert_eq!(
+            pool.deposit_commitment(account, 8, note.commitment()),
+            Err(Error::InsufficientBalance)
+        );
+        let (epoch, index) = pool
+            .deposit_commitment(account, stablecoin, note.commitment())
+            .unwrap();
+        assert_eq!(pool.get_balance(account, stablecoin), 0);
+        assert_eq!(pool.get_balance(account, NATIVE_ASSET), pool.amount() * 10);
+
+        let path = pool.find_path(epoch, index).unwrap();
+        let proof = note
+            .prove(&spending_key, &path, &Payout::new(456), &proving_key, rng)
+            .unwrap();
+        let mut claim = withdrawal(epoch, path.construct_root(), note.nullifier(), 456);
+        // A note of one asset cannot be withdrawn as another.
+        assert_eq!(pool.withdraw(&claim, &proof), Err(Error::InvalidProof));
+        claim.asset = stablecoin;
+        assert!(pool.withdraw(&claim, &proof).is_ok());
+        assert_eq!(pool.get_balance(456, stablecoin), pool.amount());
+        assert_eq!(pool.get_balance(456, NATIVE_ASSET), 0);
+    }
+
+    #[test]
+    fn should_pay_relayer_fee() {
+        let rng = &mut thread_rng();
+        let (proving_key, verifying_key) = zk::setup(rng).unwrap();
+        let mut pool = AnonymityPool::new(&verifying_key);
+
+        let spending_key = SpendingKey::random(rng);
+        let note = Note::random(rng, pool.amount(), NATIVE_ASSET, spending_key.public_key());
+        let (epoch, index) = pool
+            .deposit_commitment(AnonymityPool::account(), NATIVE_ASSET, note.commitment())
+            .unwrap();
+        let path = pool.find_path(epoch, index).unwrap();
+        let proof = note
+            .prove(
+                &spending_key,
+                &path,
+                &Payout::new(456).with_relayer(789, 30),
+                &proving_key,
+                rng,
+            )
+            .unwrap();
+
+        let mut claim = withdrawal(epoch, path.construct_root(), note.nullifier(), 456);
+        claim.payout.relayer = 789;
+        claim.payout.fee = pool.amount() + 1;
+        assert_eq!(pool.withdraw(&claim, &proof), Err(Error::FeeTooHigh));
+        // The relayer and the fee are bound to the proof.
+        claim.payout.fee = 50;
+        assert_eq!(pool.withdraw(&claim, &proof), Err(Error::InvalidProof));
+        claim.payout.fee = 30;
+        claim.payout.relayer = 790;
+        assert_eq!(pool.withdraw(&claim, &proof), Err(Error::InvalidProof));
+        claim.payout.relayer = 789;
+        assert!(pool.withdraw(&claim, &proof).is_ok());
+        assert_eq!(pool.get_balance(456, NATIVE_ASSET), pool.amount() - 30);
+        assert_eq!(pool.get_balance(789, NATIVE_ASSET), 30);
+    }
+
+    #[test]
+    fn should_split_and_merge_notes() {
+        let rng = &mut thread_rng();
+        let (proving_key, verifying_key) = zk::setup(rng).unwrap();
+        let (transfer_proving_key, transfer_verifying_key) = zk::setup_transfer(rng).unwrap();
+        let mut pool = AnonymityPool::new(&verifying_key);
+        let alice = SpendingKey::random(rng);
+        let bob = SpendingKey::random(rng);
+
+        let note = Note::random(rng, pool.denomination(), NATIVE_ASSET, alice.public_key());
+        let (epoch, index) = pool
+            .deposit_commitment(AnonymityPool::account(), NATIVE_ASSET, note.commitment())
+            .unwrap();
+        let balances = pool.balances();
+
+        // Alice pays Bob 300 and keeps 700 as change.
+        let inputs = vec![(note.clone(), pool.find_path(epoch, index).unwrap())];
+        let payment = Note::random(rng, 300, NATIVE_ASSET, bob.public_key());
+        let change = Note::random(rng, 700, NATIVE_ASSET, alice.public_key());
+        let outputs = vec![payment.clone(), change.clone()];
+        let proof = prove_transfer(&alice, &inputs, &outputs, &transfer_proving_key, rng).unwrap();
+        let split = Transfer {
+            asset: NATIVE_ASSET,
+            epoch,
+            root: inputs[0].1.construct_root(),
+            nullifiers: vec![note.nullifier()],
+            commitments: vec![payment.commitment(), change.commitment()],
+        };
+        assert_eq!(pool.transfer(&split, &proof), Err(Error::Unsupported));
+        let mut pool = pool.with_transfer_key(&transfer_verifying_key);
+        let mut forged = split.clone();
+        forged.commitments[0] =
+            Note::random(rng, 300, NATIVE_ASSET, alice.public_key()).commitment();
+        assert_eq!(pool.transfer(&forged, &proof), Err(Error::InvalidProof));
+        assert_eq!(pool.transfer(&split, &proof), Ok(vec![(0, 1), (0, 2)]));
+        assert_eq!(pool.transfer(&split, &proof), Err(Error::NullifierSpent));
+        // Nor under an alias the verifier reduces to the same nullifier.
+        let mut again = split.clone();
+        again.nullifiers[0] = alias(&note.nullifier());
+        assert_eq!(pool.transfer(&again, &proof), Err(Error::NonCanonicalField));
+        assert_eq!(
+            prove_transfer(&alice, &inputs, &outputs[..1], &transfer_proving_key, rng).unwrap_err(),
+            Error::InvalidTransfer
+        );
+        // Shielded transfers leave the ledger alone.
+        assert_eq!(pool.balances(), balances);
+
+        // Bob cannot withdraw part of a denomination, but can once Alice
+        // sends him the rest.
+        let path = pool.find_path(0, 1).unwrap();
+        let proof = payment
+            .prove(&bob, &path, &Payout::new(456), &proving_key, rng)
+            .unwrap();
+        let claim = withdrawal(0, path.construct_root(), payment.nullifier(), 456);
+        assert_eq!(pool.withdraw(&claim, &proof), Err(Error::InvalidProof));
+
+        let inputs = vec![(change.clone(), pool.find_path(0, 2).unwrap())];
+        let rest = Note::random(rng, 700, NATIVE_ASSET, bob.public_key());
+        let proof = prove_transfer(
+            &alice,
+            &inputs,
+            std::slice::from_ref(&rest),
+            &transfer_proving_key,
+            rng,
+        )
+        .unwrap();
+        let send = Transfer {
+            asset: NATIVE_ASSET,
+            epoch: 0,
+            root: inputs[0].1.construct_root(),
+            nullifiers: vec![change.nullifier()],
+            commitments: vec![rest.commitment()],
+        };
+        assert_eq!(pool.transfer(&send, &proof), Ok(vec![(0, 3)]));
+
+        let inputs = vec![
+            (payment.clone(), pool.find_path(0, 1).unwrap()),
+            (rest.clone(), pool.find_path(0, 3).unwrap()),
+        ];
+        let whole = Note::random(rng, pool.denomination(), NATIVE_ASSET, bob.public_key());
+        let proof = prove_transfer(
+            &bob,
+            &inputs,
+            std::slice::from_ref(&whole),
+            &transfer_proving_key,
+            rng,
+        )
+        .unwrap();
+        let merge = Transfer {
+            asset: NATIVE_ASSET,
+            epoch: 0,
+            root: inputs[0].1.construct_root(),
+            nullifiers: vec![payment.nullifier(), rest.nullifier()],
+            commitments: vec![whole.commitment()],
+        };
+        let mut twice = merge.clone();
+        twice.nullifiers[1] = payment.nullifier();
+        assert_eq!(pool.transfer(&twice, &proof), Err(Error::InvalidTransfer));
+        twice.nullifiers[1] = alias(&payment.nullifier());
+        assert_eq!(pool.transfer(&twice, &proof), Err(Error::NonCanonicalField));
+        let mut aliased = merge.clone();
+        aliased.commitments[0] = alias(&whole.commitment());
+        assert_eq!(
+            pool.transfer(&aliased, &proof),
+            Err(Error::NonCanonicalField)
+        );
+        assert_eq!(pool.transfer(&merge, &proof), Ok(vec![(0, 4)]));
+
+        let path = pool.find_path(0, 4).unwrap();
+        let proof = whole
+            .prove(&bob, &path, &Payout::new(456), &proving_key, rng)
+            .unwrap();
+        let claim = withdrawal(0, path.construct_root(), whole.nullifier(), 456);
+        assert!(pool.withdraw(&claim, &proof).is_ok());
+        assert_eq!(pool.get_balance(456, NATIVE_ASSET), pool.denomination());
+    }
 
-        self.balances
-            .entry(CONTRACT_ADDRESS)
-            .and_modify(|x| *x -= DEFAULT_AMOUNT);
-        self.balances
-            .entry(note.recipiant)
-            .and_modify(|x| *x += DEFAULT_AMOUNT);
+    #[test]
+    fn transfer_outputs_should_roll_over_to_new_epoch() {
+        let rng = &mut thread_rng();
+        let (_, verifying_key) = zk::setup(rng).unwrap();
+        let (transfer_proving_key, transfer_verifying_key) = zk::setup_transfer(rng).unwrap();
+        let mut pool = AnonymityPool::new(&verifying_key)
+            .with_transfer_key(&transfer_verifying_key)
+            .with_epoch_capacity(2);
+        let spending_key = SpendingKey::random(rng);
 
-        self.nullifiers.insert(nullifier, true);
+        let note = Note::random(rng, pool.amount(), NATIVE_ASSET, spending_key.public_key());
+        let (epoch, index) = pool
+            .deposit_commitment(AnonymityPool::account(), NATIVE_ASSET, note.commitment())
+            .unwrap();
+        let inputs = vec![(note.clone(), pool.find_path(epoch, index).unwrap())];
+        let outputs = vec![
+            Note::random(rng, 400, NATIVE_ASSET, spending_key.public_key()),
+            Note::random(rng, 600, NATIVE_ASSET, spending_key.public_key()),
+        ];
+        let proof =
+            prove_transfer(&spending_key, &inputs, &outputs, &transfer_proving_key, rng).unwrap();
+        let transfer = Transfer {
+            asset: NATIVE_ASSET,
+            epoch,
+            root: inputs[0].1.construct_root(),
+            nullifiers: vec![note.nullifier()],
+            commitments: outputs.iter().map(Note::commitment).collect(),
+        };
 
-        true
+        // The second output starts a new epoch; the input is spent once both
+        // are in.
+        assert_eq!(pool.transfer(&transfer, &proof), Ok(vec![(0, 1), (1, 0)]));
+        assert!(pool.nullifiers().contains(&note.nullifier()));
+        let path = pool.find_path(1, 0).unwrap();
+        assert_eq!(pool.latest_root(), Some(path.construct_root()));
     }
 }
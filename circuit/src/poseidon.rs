@@ -0,0 +1,223 @@
+use crate::{Error, Field, Hash};
+use ark_bn254::Fr;
+use ark_ff::{BigInteger, PrimeField};
+use ark_r1cs_std::fields::{fp::FpVar, FieldVar};
+use ark_relations::r1cs::SynthesisError;
+use light_poseidon::{
+    parameters::bn254_x5::get_poseidon_parameters, Poseidon as PoseidonSponge, PoseidonHasher,
+};
+use sha3::digest::{consts::U32, FixedOutput, HashMarker, Output, OutputSizeUser, Reset, Update};
+
+/// Maximum number of field elements a single circom Poseidon instance absorbs.
+const MAX_INPUTS: usize = 12;
+
+/// Poseidon over the BN254 scalar field with circomlib parameters.
+///
+/// Implements `Digest` so it can back the Merkle hashers in `hasher` and
+/// `hash_two`. The input must be 1 to 12 canonical field elements, each as a
+/// 32-byte big-endian word, which is all the hashers ever feed it. Any other
+/// input digests to `INVALID`, which is not a field element, so it cannot
+/// collide with a real hash and is rejected wherever a canonical hash is
+/// expected.
+#[derive(Debug, Clone, Default)]
+pub struct Poseidon {
+    buffer: Vec<u8>,
+}
+
+/// Digest of an input `Poseidon` does not accept.
+pub const INVALID: [u8; 32] = [0xff; 32];
+
+impl HashMarker for Poseidon {}
+
+impl OutputSizeUser for Poseidon {
+    type OutputSize = U32;
+}
+
+impl Update for Poseidon {
+    fn update(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+}
+
+impl Reset for Poseidon {
+    fn reset(&mut self) {
+        self.buffer.clear();
+    }
+}
+
+impl Poseidon {
+    fn words(&self) -> Result<Vec<Fr>, Error> {
+        if self.buffer.len() % 32 != 0 {
+            return Err(Error::InvalidHashInput);
+        }
+        self.buffer
+            .chunks(32)
+            .map(|chunk| {
+                let mut word = Hash::default();
+                word.0.copy_from_slice(chunk);
+                Field::from_hash(&word).map(|field| *field.inner())
+            })
+            .collect()
+    }
+}
+
+impl FixedOutput for Poseidon {
+    fn finalize_into(self, out: &mut Output<Self>) {
+        match self.words().and_then(|words| poseidon(&words)) {
+            Ok(hash) => out.copy_from_slice(&hash.into_bigint().to_bytes_be()),
+            Err(_) => out.copy_from_slice(&INVALID),
+        }
+    }
+}
+
+/// Poseidon hash of 1 to 12 field elements, identical to circomlib's
+/// `Poseidon(n)` template. Other arities do not compile.
+pub fn hash<const N: usize>(inputs: &[Field; N]) -> Field {
+    const { assert!(N >= 1 && N <= MAX_INPUTS) };
+    let inputs: Vec<Fr> = inputs.iter().map(|x| *x.inner()).collect();
+    Field::from(poseidon(&inputs).expect("arity is checked at compile time"))
+}
+
+pub(crate) fn poseidon(inputs: &[Fr]) -> Result<Fr, Error> {
+    if inputs.is_empty() || inputs.len() > MAX_INPUTS {
+        return Err(Error::InvalidHashInput);
+    }
+    let mut hasher =
+        PoseidonSponge::<Fr>::new_circom(inputs.len()).map_err(|_| Error::InvalidHashInput)?;
+    hasher.hash(inputs).map_err(|_| Error::InvalidHashInput)
+}
+
+pub(crate) fn hash_to_field(hash: &Hash) -> Fr {
+    Fr::from_be_bytes_mod_order(&hash.0)
+}
+
+pub(crate) fn field_to_hash(field: Fr) -> Hash {
+    let mut bytes = [0; 32];
+    bytes.copy_from_slice(&field.into_bigint().to_bytes_be());
+    Hash(bytes)
+}
+
+/// In-circuit Poseidon, mirroring `light_poseidon` with circomlib parameters.
+pub(crate) fn poseidon_var(inputs: &[FpVar<Fr>]) -> Result<FpVar<Fr>, SynthesisError> {
+    let width = inputs.len() + 1;
+    let params =
+        get_poseidon_parameters::<Fr>(width as u8).map_err(|_| SynthesisError::Unsatisfiable)?;
+    let half_rounds = params.full_rounds / 2;
+    let all_rounds = params.full_rounds + params.partial_rounds;
+
+    let mut state = Vec::with_capacity(width);
+    state.push(FpVar::zero());
+    state.extend_from_slice(inputs);
+
+    for round in 0..all_rounds {
+        for (i, a) in state.iter_mut().enumerate() {
+            *a += params.ark[round * width + i];
+        }
+        if round < half_rounds || round >= half_rounds + params.partial_rounds {
+            for a in state.iter_mut() {
+                *a = sbox(a)?;
+            }
+        } else {
+            state[0] = sbox(&state[0])?;
+        }
+        state = params
+            .mds
+            .iter()
+            .map(|row| {
+                state
+                    .iter()
+                    .zip(row)
+                    .fold(FpVar::zero(), |acc, (a, m)| acc + a * *m)
+            })
+            .collect();
+    }
+
+    Ok(state.swap_remove(0))
+}
+
+fn sbox(a: &FpVar<Fr>) -> Result<FpVar<Fr>, SynthesisError> {
+    let a2 = a.square()?;
+    let a4 = a2.square()?;
+    Ok(a4 * a)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{hash, poseidon, poseidon_var, Poseidon, INVALID};
+    use crate::{hash_leaf, hash_two, Field, Hash};
+    use ark_bn254::Fr;
+    use ark_r1cs_std::{alloc::AllocVar, fields::fp::FpVar, R1CSVar};
+    use ark_relations::r1cs::ConstraintSystem;
+
+    #[test]
+    fn should_match_circomlib_vectors() {
+        // Reference values from circomlibjs `poseidon` test suite.
+        let [zero, one, two, three, four] = [0, 1, 2, 3, 4].map(Field::from);
+        assert_eq!(
+            hash(&[one]).to_hash().to_hex(),
+            "29176100eaa962bdc1fe6c654d6a3c130e96a4d1168b33848b897dc502820133"
+        );
+        assert_eq!(
+            hash(&[one, two]).to_hash().to_hex(),
+            "115cc0f5e7d690413df64c6b9662e9cf2a3617f2743245519e19607a4417189a"
+        );
+        assert_eq!(
+            hash(&[zero, zero]).to_hash().to_hex(),
+            "2098f5fb9e239eab3ceac3f27b81e481dc3124d55ffed523a839ee8446b64864"
+        );
+        assert_eq!(
+            hash(&[one, two, three, four]).to_hash().to_hex(),
+            "299c867db6c1fdd79dcefa40e4510b9837e60ebb1ce0663dbaa525df65250465"
+        );
+    }
+
+    #[test]
+    fn digest_reads_big_endian_field_elements() {
+        let left = Field::from(1).to_hash();
+        let right = Field::from(2).to_hash();
+        assert_eq!(
+            hash_two::<Poseidon>(left.clone(), right),
+            hash(&[Field::from(1), Field::from(2)]).to_hash()
+        );
+        assert_eq!(
+            hash_leaf::<Poseidon>(left.inner().to_vec()),
+            hash(&[Field::from(1)]).to_hash()
+        );
+    }
+
+    #[test]
+    fn digest_should_only_accept_field_elements() {
+        let invalid = Hash::from_hex(hex::encode(INVALID)).unwrap();
+        // Partial words would otherwise collide with their zero-padded form.
+        assert_eq!(hash_leaf::<Poseidon>(vec![0x01]), invalid);
+        assert_eq!(hash_leaf::<Poseidon>(vec![0x00, 0x01]), invalid);
+        assert_eq!(hash_leaf::<Poseidon>(Vec::new()), invalid);
+        // Non-canonical words would otherwise collide with their reduction.
+        assert_eq!(hash_leaf::<Poseidon>(vec![0xff; 32]), invalid);
+        // More words than a single Poseidon instance absorbs.
+        let one = Field::from(1).to_hash();
+        assert_eq!(hash_leaf::<Poseidon>(one.inner().repeat(13)), invalid);
+        assert_ne!(hash_leaf::<Poseidon>(one.inner().repeat(12)), invalid);
+        assert!(Field::from_hash(&invalid).is_err());
+    }
+
+    #[test]
+    fn should_reject_non_canonical_field() {
+        let max = Hash::from_hex("f".repeat(64)).unwrap();
+        assert!(Field::from_hash(&max).is_err());
+        let one = Field::from(1);
+        assert_eq!(Field::from_hash(&one.to_hash()).unwrap(), one);
+    }
+
+    #[test]
+    fn poseidon_gadget_matches_native() {
+        let cs = ConstraintSystem::<Fr>::new_ref();
+        let inputs = [Fr::from(1u64), Fr::from(2u64)];
+        let vars: Vec<_> = inputs
+            .iter()
+            .map(|x| FpVar::new_witness(cs.clone(), || Ok(*x)).unwrap())
+            .collect();
+        let out = poseidon_var(&vars).unwrap();
+        assert_eq!(out.value().unwrap(), poseidon(&inputs).unwrap());
+    }
+}
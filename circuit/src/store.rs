@@ -0,0 +1,445 @@
+use crate::{Error as MerkleError, Hash};
+#[cfg(feature = "redb")]
+use redb::{Database, ReadableTableMetadata, TableDefinition};
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    fs::{File, OpenOptions},
+    io::{BufWriter, Read, Write},
+    path::Path,
+};
+
+/// Storage of the nodes and leaf values of a `DenseIncrementalMerkleTree`.
+///
+/// Nodes are addressed by `(level, index)`, level 0 being the hashed leaves.
+/// Writes may be buffered until `flush`, which the tree calls after every
+/// update.
+pub trait NodeStore: Send + Sync {
+    fn node(&self, level: u32, index: u64) -> Result<Option<Hash>, MerkleError>;
+
+    fn set_node(&mut self, level: u32, index: u64, node: Hash) -> Result<(), MerkleError>;
+
+    fn remove_node(&mut self, level: u32, index: u64) -> Result<(), MerkleError>;
+
+    fn num_leaves(&self) -> Result<u64, MerkleError>;
+
+    fn leaf(&self, index: u64) -> Result<Option<Hash>, MerkleError>;
+
+    /// Leaf values from index `from` onwards, in insertion order.
+    fn leaves(&self, from: u64) -> Result<Vec<Hash>, MerkleError>;
+
+    fn push_leaf(&mut self, leaf: Hash) -> Result<(), MerkleError>;
+
+    /// Keeps only the first `size` leaf values.
+    fn truncate_leaves(&mut self, size: u64) -> Result<(), MerkleError>;
+
+    fn flush(&mut self) -> Result<(), MerkleError> {
+        Ok(())
+    }
+}
+
+fn storage_error(e: impl Display) -> MerkleError {
+    MerkleError::Storage(e.to_string())
+}
+
+fn hash_from_slice(bytes: &[u8]) -> Result<Hash, MerkleError> {
+    let bytes: [u8; 32] = bytes.try_into().map_err(|_| MerkleError::CorruptStore)?;
+    Ok(Hash(bytes))
+}
+
+/// Nodes in a `HashMap`, lost when dropped.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryStore {
+    nodes: HashMap<(u32, u64), Hash>,
+    leaves: Vec<Hash>,
+}
+
+impl NodeStore for MemoryStore {
+    fn node(&self, level: u32, index: u64) -> Result<Option<Hash>, MerkleError> {
+        Ok(self.nodes.get(&(level, index)).cloned())
+    }
+
+    fn set_node(&mut self, level: u32, index: u64, node: Hash) -> Result<(), MerkleError> {
+        self.nodes.insert((level, index), node);
+        Ok(())
+    }
+
+    fn remove_node(&mut self, level: u32, index: u64) -> Result<(), MerkleError> {
+        self.nodes.remove(&(level, index));
+        Ok(())
+    }
+
+    fn num_leaves(&self) -> Result<u64, MerkleError> {
+        Ok(self.leaves.len() as u64)
+    }
+
+    fn leaf(&self, index: u64) -> Result<Option<Hash>, MerkleError> {
+        Ok(self.leaves.get(index as usize).cloned())
+    }
+
+    fn leaves(&self, from: u64) -> Result<Vec<Hash>, MerkleError> {
+        let from = (from as usize).min(self.leaves.len());
+        Ok(self.leaves[from..].to_vec())
+    }
+
+    fn push_leaf(&mut self, leaf: Hash) -> Result<(), MerkleError> {
+        self.leaves.push(leaf);
+        Ok(())
+    }
+
+    fn truncate_leaves(&mut self, size: u64) -> Result<(), MerkleError> {
+        self.leaves.truncate(size as usize);
+        Ok(())
+    }
+}
+
+const SET_NODE: u8 = 0;
+const REMOVE_NODE: u8 = 1;
+const PUSH_LEAF: u8 = 2;
+const TRUNCATE_LEAVES: u8 = 3;
+
+/// Append-only log of every store update, replayed into memory on open.
+///
+/// Records are `SET_NODE level index node`, `REMOVE_NODE level index`,
+/// `PUSH_LEAF leaf` and `TRUNCATE_LEAVES size`, integers big-endian. A record
+/// cut short by a crash is dropped on open. The log is never compacted.
+#[derive(Debug)]
+pub struct FileStore {
+    log: BufWriter<File>,
+    memory: MemoryStore,
+}
+
+impl FileStore {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, MerkleError> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(path)
+            .map_err(storage_error)?;
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes).map_err(storage_error)?;
+
+        let mut memory = MemoryStore::default();
+        let mut offset = 0;
+        while let Some(len) = Self::replay(&mut memory, &bytes[offset..])? {
+            offset += len;
+        }
+        if offset < bytes.len() {
+            file.set_len(offset as u64).map_err(storage_error)?;
+        }
+
+        Ok(Self {
+            log: BufWriter::new(file),
+            memory,
+        })
+    }
+
+    /// Applies the record at the start of `bytes`, returning its length, or
+    /// `None` if no complete record is left.
+    fn replay(memory: &mut MemoryStore, bytes: &[u8]) -> Result<Option<usize>, MerkleError> {
+        let Some((tag, body)) = bytes.split_first() else {
+            return Ok(None);
+        };
+        let len = match *tag {
+            SET_NODE => 12 + 32,
+            REMOVE_NODE => 12,
+            PUSH_LEAF => 32,
+            TRUNCATE_LEAVES => 8,
+            _ => return Err(MerkleError::CorruptStore),
+        };
+        if body.len() < len {
+            return Ok(None);
+        }
+        let u32_at = |at: usize| u32::from_be_bytes(body[at..at + 4].try_into().unwrap());
+        let u64_at = |at: usize| u64::from_be_bytes(body[at..at + 8].try_into().unwrap());
+
+        match *tag {
+            SET_NODE => memory.set_node(u32_at(0), u64_at(4), hash_from_slice(&body[12..44])?)?,
+            REMOVE_NODE => memory.remove_node(u32_at(0), u64_at(4))?,
+            PUSH_LEAF => memory.push_leaf(hash_from_slice(&body[..32])?)?,
+            _ => memory.truncate_leaves(u64_at(0))?,
+        }
+        Ok(Some(1 + len))
+    }
+
+    fn append(&mut self, tag: u8, fields: &[&[u8]]) -> Result<(), MerkleError> {
+        self.log.write_all(&[tag]).map_err(storage_error)?;
+        for field in fields {
+            self.log.write_all(field).map_err(storage_error)?;
+        }
+        Ok(())
+    }
+}
+
+impl NodeStore for FileStore {
+    fn node(&self, level: u32, index: u64) -> Result<Option<Hash>, MerkleError> {
+        self.memory.node(level, index)
+    }
+
+    fn set_node(&mut self, level: u32, index: u64, node: Hash) -> Result<(), MerkleError> {
+        self.append(
+            SET_NODE,
+            &[&level.to_be_bytes(), &index.to_be_bytes(), node.inner()],
+        )?;
+        self.memory.set_node(level, index, node)
+    }
+
+    fn remove_node(&mut self, level: u32, index: u64) -> Result<(), MerkleError> {
+        self.append(REMOVE_NODE, &[&level.to_be_bytes(), &index.to_be_bytes()])?;
+        self.memory.remove_node(level, index)
+    }
+
+    fn num_leaves(&self) -> Result<u64, MerkleError> {
+        self.memory.num_leaves()
+    }
+
+    fn leaf(&self, index: u64) -> Result<Option<Hash>, MerkleError> {
+        self.memory.leaf(index)
+    }
+
+    fn leaves(&self, from: u64) -> Result<Vec<Hash>, MerkleError> {
+        self.memory.leaves(from)
+    }
+
+    fn push_leaf(&mut self, leaf: Hash) -> Result<(), MerkleError> {
+        self.append(PUSH_LEAF, &[leaf.inner()])?;
+        self.memory.push_leaf(leaf)
+    }
+
+    fn truncate_leaves(&mut self, size: u64) -> Result<(), MerkleError> {
+        self.append(TRUNCATE_LEAVES, &[&size.to_be_bytes()])?;
+        self.memory.truncate_leaves(size)
+    }
+
+    fn flush(&mut self) -> Result<(), MerkleError> {
+        self.log.flush().map_err(storage_error)?;
+        self.log.get_ref().sync_data().map_err(storage_error)
+    }
+}
+
+#[cfg(feature = "redb")]
+const NODES: TableDefinition<(u32, u64), &[u8]> = TableDefinition::new("nodes");
+#[cfg(feature = "redb")]
+const LEAVES: TableDefinition<u64, &[u8]> = TableDefinition::new("leaves");
+
+/// Nodes in a redb database. Updates are buffered and committed in a single
+/// transaction on `flush`. Requires the `redb` feature.
+#[cfg(feature = "redb")]
+pub struct RedbStore {
+    db: Database,
+    nodes: HashMap<(u32, u64), Option<Hash>>,
+    stored_leaves: u64,
+    kept_leaves: u64,
+    leaves: Vec<Hash>,
+}
+
+#[cfg(feature = "redb")]
+impl RedbStore {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, MerkleError> {
+        let db = Database::create(path).map_err(storage_error)?;
+        let txn = db.begin_write().map_err(storage_error)?;
+        let stored_leaves = {
+            txn.open_table(NODES).map_err(storage_error)?;
+            let leaves = txn.open_table(LEAVES).map_err(storage_error)?;
+            leaves.len().map_err(storage_error)?
+        };
+        txn.commit().map_err(storage_error)?;
+
+        Ok(Self {
+            db,
+            nodes: HashMap::new(),
+            stored_leaves,
+            kept_leaves: stored_leaves,
+            leaves: Vec::new(),
+        })
+    }
+
+    fn stored_leaves(&self, from: u64, to: u64) -> Result<Vec<Hash>, MerkleError> {
+        let txn = self.db.begin_read().map_err(storage_error)?;
+        let table = txn.open_table(LEAVES).map_err(storage_error)?;
+        table
+            .range(from..to)
+            .map_err(storage_error)?
+            .map(|entry| {
+                let (_, leaf) = entry.map_err(storage_error)?;
+                hash_from_slice(leaf.value())
+            })
+            .collect()
+    }
+}
+
+#[cfg(feature = "redb")]
+impl NodeStore for RedbStore {
+    fn node(&self, level: u32, index: u64) -> Result<Option<Hash>, MerkleError> {
+        if let Some(node) = self.nodes.get(&(level, index)) {
+            return Ok(node.clone());
+        }
+        let txn = self.db.begin_read().map_err(storage_error)?;
+        let table = txn.open_table(NODES).map_err(storage_error)?;
+        let node = table.get((level, index)).map_err(storage_error)?;
+        node.map(|node| hash_from_slice(node.value())).transpose()
+    }
+
+    fn set_node(&mut self, level: u32, index: u64, node: Hash) -> Result<(), MerkleError> {
+        self.nodes.insert((level, index), Some(node));
+        Ok(())
+    }
+
+    fn remove_node(&mut self, level: u32, index: u64) -> Result<(), MerkleError> {
+        self.nodes.insert((level, index), None);
+        Ok(())
+    }
+
+    fn num_leaves(&self) -> Result<u64, MerkleError> {
+        Ok(self.kept_leaves + self.leaves.len() as u64)
+    }
+
+    fn leaf(&self, index: u64) -> Result<Option<Hash>, MerkleError> {
+        if index >= self.kept_leaves {
+            return Ok(self
+                .leaves
+                .get((index - self.kept_leaves) as usize)
+                .cloned());
+        }
+        Ok(self.stored_leaves(index, index + 1)?.pop())
+    }
+
+    fn leaves(&self, from: u64) -> Result<Vec<Hash>, MerkleError> {
+        let mut leaves = if from < self.kept_leaves {
+            self.stored_leaves(from, self.kept_leaves)?
+        } else {
+            Vec::new()
+        };
+        let pending = from.saturating_sub(self.kept_leaves) as usize;
+        leaves.extend_from_slice(&self.leaves[pending.min(self.leaves.len())..]);
+        Ok(leaves)
+    }
+
+    fn push_leaf(&mut self, leaf: Hash) -> Result<(), MerkleError> {
+        self.leaves.push(leaf);
+        Ok(())
+    }
+
+    fn truncate_leaves(&mut self, size: u64) -> Result<(), MerkleError> {
+        if size <= self.kept_leaves {
+            self.kept_leaves = size;
+            self.leaves.clear();
+        } else {
+            self.leaves.truncate((size - self.kept_leaves) as usize);
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), MerkleError> {
+        let txn = self.db.begin_write().map_err(storage_error)?;
+        {
+            let mut nodes = txn.open_table(NODES).map_err(storage_error)?;
+            for ((level, index), node) in self.nodes.drain() {
+                match node {
+                    Some(node) => nodes.insert((level, index), node.inner().as_slice()),
+                    None => nodes.remove((level, index)),
+                }
+                .map_err(storage_error)?;
+            }
+
+            let mut leaves = txn.open_table(LEAVES).map_err(storage_error)?;
+            for index in self.kept_leaves..self.stored_leaves {
+                leaves.remove(index).map_err(storage_error)?;
+            }
+            for (index, leaf) in (self.kept_leaves..).zip(self.leaves.drain(..)) {
+                leaves
+                    .insert(index, leaf.inner().as_slice())
+                    .map_err(storage_error)?;
+            }
+            self.stored_leaves = leaves.len().map_err(storage_error)?;
+            self.kept_leaves = self.stored_leaves;
+        }
+        txn.commit().map_err(storage_error)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[cfg(feature = "redb")]
+    use super::RedbStore;
+    use super::{FileStore, NodeStore};
+    use crate::{hasher::Tagged, merkle::DenseIncrementalMerkleTree, Error, Hash};
+    use rand::thread_rng;
+    use sha3::Keccak256;
+    use std::{fs::OpenOptions, io::Write};
+
+    type Tree<S> = DenseIncrementalMerkleTree<Tagged<Keccak256>, 8, S>;
+
+    fn should_survive_reopen<S: NodeStore>(open: impl Fn() -> S) {
+        let rng = &mut thread_rng();
+        let leaves: Vec<Hash> = (0..30).map(|_| Hash::random(rng)).collect();
+        let reference = Tree::from_leaves(leaves.clone()).unwrap();
+
+        let mut tree = Tree::open(open()).unwrap();
+        tree.extend(leaves[..20].to_vec()).unwrap();
+        tree.checkpoint(1);
+        tree.insert_leaf(Hash::random(rng)).unwrap();
+        tree.rewind_to(1).unwrap();
+        drop(tree);
+
+        let mut tree = Tree::open(open()).unwrap();
+        assert_eq!(tree.size(), 20);
+        for leaf in &leaves[20..] {
+            tree.insert_leaf(leaf.clone()).unwrap();
+        }
+        drop(tree);
+
+        let tree = Tree::open(open()).unwrap();
+        assert_eq!(tree.root(), reference.root());
+        assert_eq!(tree.leaves(0).unwrap(), leaves);
+        assert_eq!(
+            tree.find_path(7).unwrap().neighbours(),
+            reference.find_path(7).unwrap().neighbours()
+        );
+    }
+
+    #[test]
+    fn file_store_should_survive_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("nodes.log");
+        should_survive_reopen(|| FileStore::open(&path).unwrap());
+    }
+
+    #[cfg(feature = "redb")]
+    #[test]
+    fn redb_store_should_survive_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("nodes.redb");
+        should_survive_reopen(|| RedbStore::open(&path).unwrap());
+    }
+
+    #[test]
+    fn file_store_should_recover_or_reject_on_open() {
+        let rng = &mut thread_rng();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("nodes.log");
+        let mut tree = Tree::open(FileStore::open(&path).unwrap()).unwrap();
+        tree.insert_leaf(Hash::random(rng)).unwrap();
+        let root = tree.root().unwrap();
+        drop(tree);
+
+        // A record torn by a crash is dropped.
+        let mut log = OpenOptions::new().append(true).open(&path).unwrap();
+        log.write_all(&[super::PUSH_LEAF, 1, 2, 3]).unwrap();
+        drop(log);
+        let tree = Tree::open(FileStore::open(&path).unwrap()).unwrap();
+        assert_eq!(tree.root().unwrap(), root);
+        drop(tree);
+
+        // A leaf whose nodes never made it to the log fails the root check.
+        let mut log = OpenOptions::new().append(true).open(&path).unwrap();
+        log.write_all(&[super::PUSH_LEAF]).unwrap();
+        log.write_all(Hash::random(rng).inner()).unwrap();
+        drop(log);
+        assert_eq!(
+            Tree::open(FileStore::open(&path).unwrap()).unwrap_err(),
+            Error::CorruptStore
+        );
+    }
+}
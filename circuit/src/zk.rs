@@ -0,0 +1,592 @@
+use crate::{
+    hasher::Domain,
+    note::{Note, SpendingKey},
+    num_to_bits_vec,
+    pool::{NotePath, Payout, Transfer, Withdrawal, TREE_DEPTH},
+    poseidon::{hash_to_field, poseidon_var},
+};
+use ark_bn254::{Bn254, Fr};
+use ark_groth16::{Groth16, PreparedVerifyingKey, Proof, ProvingKey, VerifyingKey};
+use ark_r1cs_std::{
+    alloc::AllocVar,
+    boolean::Boolean,
+    eq::EqGadget,
+    fields::{fp::FpVar, FieldVar},
+};
+use ark_relations::r1cs::{ConstraintSynthesizer, ConstraintSystemRef, SynthesisError};
+use ark_snark::SNARK;
+use ark_std::rand::{CryptoRng, RngCore};
+
+pub type WithdrawProof = Proof<Bn254>;
+pub type WithdrawProvingKey = ProvingKey<Bn254>;
+pub type WithdrawVerifyingKey = VerifyingKey<Bn254>;
+
+pub type TransferProof = Proof<Bn254>;
+pub type TransferProvingKey = ProvingKey<Bn254>;
+pub type TransferVerifyingKey = VerifyingKey<Bn254>;
+
+/// Most notes a transfer spends.
+pub const TRANSFER_INPUTS: usize = 2;
+
+/// Most notes a transfer creates.
+pub const TRANSFER_OUTPUTS: usize = 2;
+
+/// Merkle path of a note as circuit witnesses, padded to `TREE_DEPTH`
+/// levels. Padding levels are disabled and pass the node through unchanged.
+#[derive(Debug, Clone)]
+struct PathWitness {
+    indices: Vec<bool>,
+    elements: Vec<Fr>,
+    enabled: Vec<bool>,
+}
+
+impl PathWitness {
+    fn new(path: &NotePath) -> Self {
+        let len = path.neighbours().len();
+        let depth = TREE_DEPTH.max(len);
+        let mut indices = num_to_bits_vec(path.index(), len);
+        indices.resize(depth, false);
+        let mut elements: Vec<Fr> = path.neighbours().iter().map(hash_to_field).collect();
+        elements.resize(depth, Fr::from(0u64));
+        let mut enabled = vec![true; len];
+        enabled.resize(depth, false);
+        Self {
+            indices,
+            elements,
+            enabled,
+        }
+    }
+
+    /// Dummy path of `TREE_DEPTH` levels.
+    fn empty(enabled: bool) -> Self {
+        Self {
+            indices: vec![false; TREE_DEPTH],
+            elements: vec![Fr::from(0u64); TREE_DEPTH],
+            enabled: vec![enabled; TREE_DEPTH],
+        }
+    }
+
+    /// Root of the tree holding `commitment` at this path.
+    fn root_var(
+        self,
+        cs: ConstraintSystemRef<Fr>,
+        commitment: FpVar<Fr>,
+    ) -> Result<FpVar<Fr>, SynthesisError> {
+        let mut node = poseidon_var(&[domain(Domain::Leaf), commitment])?;
+        let mut below_enabled = Boolean::TRUE;
+        let levels = self
+            .indices
+            .into_iter()
+            .zip(self.elements)
+            .zip(self.enabled);
+        for ((is_right, sibling), enabled) in levels {
+            let is_right = Boolean::new_witness(cs.clone(), || Ok(is_right))?;
+            let sibling = FpVar::new_witness(cs.clone(), || Ok(sibling))?;
+            let enabled = Boolean::new_witness(cs.clone(), || Ok(enabled))?;
+            // Enabled levels form a prefix of the path.
+            enabled
+                .and(&below_enabled.not())?
+                .enforce_equal(&Boolean::FALSE)?;
+            let left = is_right.select(&sibling, &node)?;
+            let right = is_right.select(&node, &sibling)?;
+            let parent = poseidon_var(&[domain(Domain::Node), left, right])?;
+            node = enabled.select(&parent, &node)?;
+            below_enabled = enabled;
+        }
+        Ok(node)
+    }
+}
+
+/// Groth16 statement for spending a note: "I know a spending key and a note
+/// opening such that the note is paid to that key, its commitment is a leaf
+/// under `root`, and its nullifier is `nullifier_hash`".
+///
+/// Public inputs, in order: `root`, `nullifier_hash`, `recipient`, `amount`,
+/// `asset`, `relayer`, `fee`. The recipient, the relayer and the fee are
+/// chosen by the spender at proving time, so nobody else can redirect the
+/// withdrawal or raise the fee.
+///
+/// The circuit has `TREE_DEPTH` levels. A shorter path, such as one of a
+/// Merkle Mountain Range, leaves its top levels disabled, so they pass the
+/// node through unchanged.
+#[derive(Debug, Clone)]
+pub struct WithdrawCircuit {
+    root: Fr,
+    nullifier_hash: Fr,
+    recipient: Fr,
+    amount: Fr,
+    asset: Fr,
+    relayer: Fr,
+    fee: Fr,
+    spending_key: Fr,
+    nullifier_key: Fr,
+    randomness: Fr,
+    path: PathWitness,
+}
+
+impl WithdrawCircuit {
+    pub fn new(note: &Note, spending_key: &SpendingKey, path: &NotePath, payout: &Payout) -> Self {
+        Self {
+            root: hash_to_field(&path.construct_root()),
+            nullifier_hash: hash_to_field(&note.nullifier()),
+            recipient: Fr::from(payout.recipient),
+            amount: Fr::from(note.amount()),
+            asset: Fr::from(note.asset()),
+            relayer: Fr::from(payout.relayer),
+            fee: Fr::from(payout.fee),
+            spending_key: *spending_key.inner().inner(),
+            nullifier_key: *note.nullifier_key().inner(),
+            randomness: *note.randomness().inner(),
+            path: PathWitness::new(path),
+        }
+    }
+
+    /// Circuit shape with dummy assignments, used for the key generation.
+    fn empty() -> Self {
+        Self {
+            root: Fr::from(0u64),
+            nullifier_hash: Fr::from(0u64),
+            recipient: Fr::from(0u64),
+            amount: Fr::from(0u64),
+            asset: Fr::from(0u64),
+            relayer: Fr::from(0u64),
+            fee: Fr::from(0u64),
+            spending_key: Fr::from(0u64),
+            nullifier_key: Fr::from(0u64),
+            randomness: Fr::from(0u64),
+            path: PathWitness::empty(true),
+        }
+    }
+}
+
+impl ConstraintSynthesizer<Fr> for WithdrawCircuit {
+    fn generate_constraints(self, cs: ConstraintSystemRef<Fr>) -> Result<(), SynthesisError> {
+        let root = FpVar::new_input(cs.clone(), || Ok(self.root))?;
+        let nullifier_hash = FpVar::new_input(cs.clone(), || Ok(self.nullifier_hash))?;
+        // Groth16 binds every public input to the proof, so the payout needs
+        // no constraints of its own.
+        let _recipient = FpVar::new_input(cs.clone(), || Ok(self.recipient))?;
+        let amount = FpVar::new_input(cs.clone(), || Ok(self.amount))?;
+        let asset = FpVar::new_input(cs.clone(), || Ok(self.asset))?;
+        let _relayer = FpVar::new_input(cs.clone(), || Ok(self.relayer))?;
+        let _fee = FpVar::new_input(cs.clone(), || Ok(self.fee))?;
+
+        let spending_key = FpVar::new_witness(cs.clone(), || Ok(self.spending_key))?;
+        let nullifier_key = FpVar::new_witness(cs.clone(), || Ok(self.nullifier_key))?;
+        let randomness = FpVar::new_witness(cs.clone(), || Ok(self.randomness))?;
+
+        let recipient_pk = poseidon_var(&[domain(Domain::PublicKey), spending_key])?;
+        let commitment = commitment_var(&nullifier_key, randomness, amount, &asset, recipient_pk)?;
+        nullifier_var(nullifier_key, commitment.clone(), &asset)?.enforce_equal(&nullifier_hash)?;
+        self.path.root_var(cs, commitment)?.enforce_equal(&root)?;
+
+        Ok(())
+    }
+}
+
+/// Note spent by a transfer. A disabled slot is worth nothing, has no path
+/// and a zero nullifier.
+#[derive(Debug, Clone)]
+struct TransferInput {
+    enabled: bool,
+    nullifier_key: Fr,
+    randomness: Fr,
+    amount: u64,
+    path: PathWitness,
+}
+
+/// Note created by a transfer. A disabled slot is worth nothing and has a
+/// zero commitment.
+#[derive(Debug, Clone)]
+struct TransferOutput {
+    enabled: bool,
+    nullifier_key: Fr,
+    randomness: Fr,
+    amount: u64,
+    recipient_pk: Fr,
+}
+
+/// Groth16 statement for a join-split transfer: "I know a spending key and
+/// the openings of up to `TRANSFER_INPUTS` notes paid to that key, under
+/// `root`, with these nullifiers, and of up to `TRANSFER_OUTPUTS` new notes
+/// with these commitments, all of `asset`, such that the amounts spent and
+/// created are equal".
+///
+/// Public inputs, in order: `root`, the input nullifiers, the output
+/// commitments and `asset`, with zero for every unused slot. Amounts are
+/// 64-bit, so the sums cannot wrap around the field.
+#[derive(Debug, Clone)]
+pub struct TransferCircuit {
+    root: Fr,
+    nullifier_hashes: Vec<Fr>,
+    commitments: Vec<Fr>,
+    asset: Fr,
+    spending_key: Fr,
+    inputs: Vec<TransferInput>,
+    outputs: Vec<TransferOutput>,
+}
+
+impl TransferCircuit {
+    /// Circuit spending `inputs` into `outputs`. The notes must share an
+    /// asset and the paths a root, with at most `TRANSFER_INPUTS` inputs and
+    /// `TRANSFER_OUTPUTS` outputs; `note::prove_transfer` checks this.
+    pub fn new(spending_key: &SpendingKey, inputs: &[(Note, NotePath)], outputs: &[Note]) -> Self {
+        let root = inputs
+            .first()
+            .map(|(_, path)| hash_to_field(&path.construct_root()))
+            .unwrap_or_default();
+        let asset = inputs
+            .first()
+            .map(|(note, _)| Fr::from(note.asset()))
+            .unwrap_or_default();
+
+        let mut nullifier_hashes: Vec<Fr> = inputs
+            .iter()
+            .map(|(note, _)| hash_to_field(&note.nullifier()))
+            .collect();
+        nullifier_hashes.resize(TRANSFER_INPUTS, Fr::from(0u64));
+        let mut commitments: Vec<Fr> = outputs
+            .iter()
+            .map(|note| hash_to_field(&note.commitment()))
+            .collect();
+        commitments.resize(TRANSFER_OUTPUTS, Fr::from(0u64));
+
+        let mut transfer_inputs: Vec<TransferInput> = inputs
+            .iter()
+            .map(|(note, path)| TransferInput {
+                enabled: true,
+                nullifier_key: *note.nullifier_key().inner(),
+                randomness: *note.randomness().inner(),
+                amount: note.amount(),
+                path: PathWitness::new(path),
+            })
+            .collect();
+        transfer_inputs.resize(TRANSFER_INPUTS, TransferInput::disabled());
+        let mut transfer_outputs: Vec<TransferOutput> = outputs
+            .iter()
+            .map(|note| TransferOutput {
+                enabled: true,
+                nullifier_key: *note.nullifier_key().inner(),
+                randomness: *note.randomness().inner(),
+                amount: note.amount(),
+                recipient_pk: *note.recipient_pk().inner(),
+            })
+            .collect();
+        transfer_outputs.resize(TRANSFER_OUTPUTS, TransferOutput::disabled());
+
+        Self {
+            root,
+            nullifier_hashes,
+            commitments,
+            asset,
+            spending_key: *spending_key.inner().inner(),
+            inputs: transfer_inputs,
+            outputs: transfer_outputs,
+        }
+    }
+
+    /// Circuit shape with dummy assignments, used for the key generation.
+    fn empty() -> Self {
+        Self {
+            root: Fr::from(0u64),
+            nullifier_hashes: vec![Fr::from(0u64); TRANSFER_INPUTS],
+            commitments: vec![Fr::from(0u64); TRANSFER_OUTPUTS],
+            asset: Fr::from(0u64),
+            spending_key: Fr::from(0u64),
+            inputs: vec![TransferInput::disabled(); TRANSFER_INPUTS],
+            outputs: vec![TransferOutput::disabled(); TRANSFER_OUTPUTS],
+        }
+    }
+}
+
+impl TransferInput {
+    fn disabled() -> Self {
+        Self {
+            enabled: false,
+            nullifier_key: Fr::from(0u64),
+            randomness: Fr::from(0u64),
+            amount: 0,
+            path: PathWitness::empty(false),
+        }
+    }
+}
+
+impl TransferOutput {
+    fn disabled() -> Self {
+        Self {
+            enabled: false,
+            nullifier_key: Fr::from(0u64),
+            randomness: Fr::from(0u64),
+            amount: 0,
+            recipient_pk: Fr::from(0u64),
+        }
+    }
+}
+
+impl ConstraintSynthesizer<Fr> for TransferCircuit {
+    fn generate_constraints(self, cs: ConstraintSystemRef<Fr>) -> Result<(), SynthesisError> {
+        let root = FpVar::new_input(cs.clone(), || Ok(self.root))?;
+        let nullifier_hashes = self
+            .nullifier_hashes
+            .iter()
+            .map(|nullifier_hash| FpVar::new_input(cs.clone(), || Ok(*nullifier_hash)))
+            .collect::<Result<Vec<_>, _>>()?;
+        let commitments = self
+            .commitments
+            .iter()
+            .map(|commitment| FpVar::new_input(cs.clone(), || Ok(*commitment)))
+            .collect::<Result<Vec<_>, _>>()?;
+        let asset = FpVar::new_input(cs.clone(), || Ok(self.asset))?;
+
+        let spending_key = FpVar::new_witness(cs.clone(), || Ok(self.spending_key))?;
+        let public_key = poseidon_var(&[domain(Domain::PublicKey), spending_key])?;
+        let zero = FpVar::zero();
+
+        let mut value_in = FpVar::zero();
+        for (input, nullifier_hash) in self.inputs.into_iter().zip(nullifier_hashes) {
+            let enabled = Boolean::new_witness(cs.clone(), || Ok(input.enabled))?;
+            let nullifier_key = FpVar::new_witness(cs.clone(), || Ok(input.nullifier_key))?;
+            let randomness = FpVar::new_witness(cs.clone(), || Ok(input.randomness))?;
+            let amount = amount_var(cs.clone(), input.amount)?;
+
+            let commitment = commitment_var(
+                &nullifier_key,
+                randomness,
+                amount.clone(),
+                &asset,
+                public_key.clone(),
+            )?;
+            let nullifier = nullifier_var(nullifier_key, commitment.clone(), &asset)?;
+            enabled
+                .select(&nullifier, &zero)?
+                .enforce_equal(&nullifier_hash)?;
+            input
+                .path
+                .root_var(cs.clone(), commitment)?
+                .conditional_enforce_equal(&root, &enabled)?;
+            amount.conditional_enforce_equal(&zero, &enabled.not())?;
+            value_in += amount;
+        }
+
+        let mut value_out = FpVar::zero();
+        for (output, commitment) in self.outputs.into_iter().zip(commitments) {
+            let enabled = Boolean::new_witness(cs.clone(), || Ok(output.enabled))?;
+            let nullifier_key = FpVar::new_witness(cs.clone(), || Ok(output.nullifier_key))?;
+            let randomness = FpVar::new_witness(cs.clone(), || Ok(output.randomness))?;
+            let recipient_pk = FpVar::new_witness(cs.clone(), || Ok(output.recipient_pk))?;
+            let amount = amount_var(cs.clone(), output.amount)?;
+
+            enabled
+                .select(
+                    &commitment_var(
+                        &nullifier_key,
+                        randomness,
+                        amount.clone(),
+                        &asset,
+                        recipient_pk,
+                    )?,
+                    &zero,
+                )?
+                .enforce_equal(&commitment)?;
+            amount.conditional_enforce_equal(&zero, &enabled.not())?;
+            value_out += amount;
+        }
+        value_in.enforce_equal(&value_out)?;
+
+        Ok(())
+    }
+}
+
+/// `H(COMMITMENT, nullifier_key, randomness, amount, asset, recipient_pk)`
+fn commitment_var(
+    nullifier_key: &FpVar<Fr>,
+    randomness: FpVar<Fr>,
+    amount: FpVar<Fr>,
+    asset: &FpVar<Fr>,
+    recipient_pk: FpVar<Fr>,
+) -> Result<FpVar<Fr>, SynthesisError> {
+    poseidon_var(&[
+        domain(Domain::Commitment),
+        nullifier_key.clone(),
+        randomness,
+        amount,
+        asset.clone(),
+        recipient_pk,
+    ])
+}
+
+/// `H(NULLIFIER, nullifier_key, commitment, asset)`
+fn nullifier_var(
+    nullifier_key: FpVar<Fr>,
+    commitment: FpVar<Fr>,
+    asset: &FpVar<Fr>,
+) -> Result<FpVar<Fr>, SynthesisError> {
+    poseidon_var(&[
+        domain(Domain::Nullifier),
+        nullifier_key,
+        commitment,
+        asset.clone(),
+    ])
+}
+
+/// Witness of a 64-bit amount, allocated bit by bit so it is range checked.
+fn amount_var(cs: ConstraintSystemRef<Fr>, amount: u64) -> Result<FpVar<Fr>, SynthesisError> {
+    let bits = (0..u64::BITS)
+        .map(|i| Boolean::new_witness(cs.clone(), || Ok(amount >> i & 1 == 1)))
+        .collect::<Result<Vec<_>, _>>()?;
+    Boolean::le_bits_to_fp_var(&bits)
+}
+
+fn domain(domain: Domain) -> FpVar<Fr> {
+    FpVar::constant(Fr::from(domain.tag()))
+}
+
+/// Generates the Groth16 keys for the withdrawal circuit of the pool tree.
+pub fn setup<R: RngCore + CryptoRng>(
+    rng: &mut R,
+) -> Result<(WithdrawProvingKey, WithdrawVerifyingKey), SynthesisError> {
+    Groth16::<Bn254>::circuit_specific_setup(WithdrawCircuit::empty(), rng)
+}
+
+pub fn prove<R: RngCore + CryptoRng>(
+    proving_key: &WithdrawProvingKey,
+    circuit: WithdrawCircuit,
+    rng: &mut R,
+) -> Result<WithdrawProof, SynthesisError> {
+    Groth16::<Bn254>::prove(proving_key, circuit, rng)
+}
+
+/// Checks a proof of spending a note of `amount` units against the public
+/// data of `withdrawal`.
+pub fn verify(
+    verifying_key: &PreparedVerifyingKey<Bn254>,
+    amount: u64,
+    withdrawal: &Withdrawal,
+    proof: &WithdrawProof,
+) -> bool {
+    let public_inputs = [
+        hash_to_field(&withdrawal.root),
+        hash_to_field(&withdrawal.nullifier_hash),
+        Fr::from(withdrawal.payout.recipient),
+        Fr::from(amount),
+        Fr::from(withdrawal.asset),
+        Fr::from(withdrawal.payout.relayer),
+        Fr::from(withdrawal.payout.fee),
+    ];
+    Groth16::<Bn254>::verify_with_processed_vk(verifying_key, &public_inputs, proof)
+        .unwrap_or(false)
+}
+
+/// Generates the Groth16 keys for the transfer circuit of the pool tree.
+pub fn setup_transfer<R: RngCore + CryptoRng>(
+    rng: &mut R,
+) -> Result<(TransferProvingKey, TransferVerifyingKey), SynthesisError> {
+    Groth16::<Bn254>::circuit_specific_setup(TransferCircuit::empty(), rng)
+}
+
+pub fn prove_transfer<R: RngCore + CryptoRng>(
+    proving_key: &TransferProvingKey,
+    circuit: TransferCircuit,
+    rng: &mut R,
+) -> Result<TransferProof, SynthesisError> {
+    Groth16::<Bn254>::prove(proving_key, circuit, rng)
+}
+
+/// Checks a transfer proof against the public data of `transfer`, padding
+/// its unused slots with zeros.
+pub fn verify_transfer(
+    verifying_key: &PreparedVerifyingKey<Bn254>,
+    transfer: &Transfer,
+    proof: &TransferProof,
+) -> bool {
+    let mut nullifiers: Vec<Fr> = transfer.nullifiers.iter().map(hash_to_field).collect();
+    nullifiers.resize(TRANSFER_INPUTS, Fr::from(0u64));
+    let mut commitments: Vec<Fr> = transfer.commitments.iter().map(hash_to_field).collect();
+    commitments.resize(TRANSFER_OUTPUTS, Fr::from(0u64));
+
+    let mut public_inputs = vec![hash_to_field(&transfer.root)];
+    public_inputs.extend(nullifiers);
+    public_inputs.extend(commitments);
+    public_inputs.push(Fr::from(transfer.asset));
+    Groth16::<Bn254>::verify_with_processed_vk(verifying_key, &public_inputs, proof)
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{TransferCircuit, WithdrawCircuit};
+    use crate::{
+        merkle::{DenseIncrementalMerkleTree, FrontierMerkleTree},
+        note::{Note, SpendingKey},
+        pool::{NotePath, Payout, PoolHasher, TREE_DEPTH},
+        Hash,
+    };
+    use ark_bn254::Fr;
+    use ark_relations::r1cs::{ConstraintSynthesizer, ConstraintSystem};
+    use rand::thread_rng;
+
+    #[test]
+    fn withdraw_circuit_is_satisfied() {
+        let rng = &mut thread_rng();
+        let spending_key = SpendingKey::random(rng);
+        let note = Note::random(rng, 1000, 0, spending_key.public_key());
+        let payout = Payout::new(456).with_relayer(789, 10);
+
+        let mut tree = FrontierMerkleTree::<PoolHasher, TREE_DEPTH>::new();
+        tree.insert_leaf(Hash::default()).unwrap();
+        let index = tree.track_leaf(note.commitment()).unwrap();
+        tree.insert_leaf(Hash::default()).unwrap();
+        let path = tree.find_path(index).unwrap();
+
+        let cs = ConstraintSystem::<Fr>::new_ref();
+        WithdrawCircuit::new(&note, &spending_key, &path, &payout)
+            .generate_constraints(cs.clone())
+            .unwrap();
+        assert!(cs.is_satisfied().unwrap());
+
+        // Only the owner of the recipient key can spend the note.
+        let cs = ConstraintSystem::<Fr>::new_ref();
+        WithdrawCircuit::new(&note, &SpendingKey::random(rng), &path, &payout)
+            .generate_constraints(cs.clone())
+            .unwrap();
+        assert!(!cs.is_satisfied().unwrap());
+    }
+
+    #[test]
+    fn transfer_circuit_should_conserve_value() {
+        let rng = &mut thread_rng();
+        let spending_key = SpendingKey::random(rng);
+        let a = Note::random(rng, 1000, 0, spending_key.public_key());
+        let b = Note::random(rng, 250, 0, spending_key.public_key());
+        let mut tree = DenseIncrementalMerkleTree::<PoolHasher, TREE_DEPTH>::new();
+        tree.insert_leaf(a.commitment()).unwrap();
+        tree.insert_leaf(b.commitment()).unwrap();
+        let inputs = vec![
+            (a.clone(), tree.find_path(0).unwrap()),
+            (b, tree.find_path(1).unwrap()),
+        ];
+        let other = SpendingKey::random(rng).public_key();
+        let is_satisfied = |inputs: &[(Note, NotePath)], outputs: &[Note]| {
+            let cs = ConstraintSystem::<Fr>::new_ref();
+            TransferCircuit::new(&spending_key, inputs, outputs)
+                .generate_constraints(cs.clone())
+                .unwrap();
+            cs.is_satisfied().unwrap()
+        };
+
+        let payment = Note::random(rng, 1100, 0, other);
+        let change = Note::random(rng, 150, 0, spending_key.public_key());
+        assert!(is_satisfied(&inputs, &[payment.clone(), change]));
+        // Unused slots are worth nothing.
+        assert!(is_satisfied(
+            &inputs[..1],
+            &[Note::random(rng, 1000, 0, other)]
+        ));
+        assert!(!is_satisfied(&inputs, &[payment]));
+        // Notes of another asset cannot be created.
+        assert!(!is_satisfied(
+            &inputs[..1],
+            &[Note::random(rng, 1000, 1, other)]
+        ));
+    }
+}
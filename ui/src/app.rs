@@ -1,5 +1,3 @@
-use std::ops::Deref;
-
 use crate::{
     bindgen::*,
     util::{
@@ -7,7 +5,8 @@ use crate::{
         UnShieldAccountProps, UnShieldedAccountState, WithdrawParams,
     },
 };
-use serde_wasm_bindgen::to_value;
+use serde_wasm_bindgen::{from_value, to_value};
+use shield_circuit::pool::NATIVE_ASSET;
 use wasm_bindgen_futures::spawn_local;
 use yew::prelude::*;
 
@@ -15,48 +14,61 @@ use yew::prelude::*;
 pub fn app() -> Html {
     let shielded_accounts = use_state(Vec::new);
     let unshielded_accounts = use_state(Vec::new);
-    let deposit_amount = use_state(|| 0);
+    let denominations = use_state(Vec::new);
+    let denomination = use_state(|| 0);
 
     {
         let unshielded_accounts = unshielded_accounts.clone();
-        let deposit_amount = deposit_amount.clone();
-        spawn_local(async move {
-            let amount_val = invoke_without_args("get_default_amount").await.as_string();
-            let amount = amount_val
-                .clone()
-                .unwrap_or("0".to_string())
-                .parse::<u64>()
-                .unwrap();
+        let denominations = denominations.clone();
+        let denomination = denomination.clone();
+        use_effect_with((), move |_| {
+            spawn_local(async move {
+                let available = invoke_without_args("get_denominations").await;
+                let available = from_value::<Vec<u64>>(available).unwrap_or_default();
 
-            deposit_amount.set(amount);
+                denomination.set(available.first().copied().unwrap_or(0));
+                denominations.set(available);
 
-            let account_val = invoke_without_args("get_default_account").await.as_string();
-            let account = account_val
-                .clone()
-                .unwrap_or("0".to_string())
-                .parse::<u64>()
-                .unwrap();
+                let account_val = invoke_without_args("get_default_account").await.as_string();
+                let account = account_val
+                    .clone()
+                    .unwrap_or("0".to_string())
+                    .parse::<u64>()
+                    .unwrap();
 
-            let js_args = to_value(&GetBalanceParams { account }).unwrap();
-            let balance = invoke("get_balance", js_args).await.as_string();
-            let balance = balance.unwrap_or("0".to_string()).parse::<u64>().unwrap();
-            let state =
-                UnShieldedAccountState::new(account_val.unwrap_or("0".to_string()), balance);
-            unshielded_accounts.set(vec![state]);
+                let js_args = to_value(&GetBalanceParams {
+                    account,
+                    asset: NATIVE_ASSET,
+                })
+                .unwrap();
+                let balance = invoke("get_balance", js_args).await.as_string();
+                let balance = balance.unwrap_or("0".to_string()).parse::<u64>().unwrap();
+                let state =
+                    UnShieldedAccountState::new(account_val.unwrap_or("0".to_string()), balance);
+                unshielded_accounts.set(vec![state]);
+            });
         });
     }
 
+    let denomination_selected = {
+        let denomination = denomination.clone();
+        Callback::from(move |selected| denomination.set(selected))
+    };
+
     let deposit_click = {
         let shielded_accounts = shielded_accounts.clone();
+        let denomination = denomination.clone();
         Callback::from(move |new_shielded_addr| {
             let shielded_accounts = shielded_accounts.clone();
+            let denomination = *denomination;
             spawn_local(async move {
                 let mut accounts = shielded_accounts.to_vec();
 
                 let account_id = accounts.len();
 
                 let js_args = to_value(&DepositParams {
-                    recipiant: account_id as u64,
+                    denomination,
+                    asset: NATIVE_ASSET,
                 })
                 .unwrap();
                 let nullifier_str = invoke("deposit", js_args).await;
@@ -64,6 +76,7 @@ pub fn app() -> Html {
                 accounts.push(ShieldedAccountState::new(
                     account_id,
                     new_shielded_addr,
+                    denomination,
                     false,
                     nullifier_str.as_string().unwrap(),
                 ));
@@ -74,9 +87,13 @@ pub fn app() -> Html {
 
     let withdraw_click = {
         let shielded_accounts = shielded_accounts.clone();
-        Callback::from(move |(id, nullifier)| {
+        Callback::from(move |(id, nullifier, denomination)| {
             let shielded_accounts = shielded_accounts.clone();
-            let js_args = to_value(&WithdrawParams::from_hex_str(nullifier)).unwrap();
+            let params = match WithdrawParams::from_hex_str(nullifier, denomination, id as u64) {
+                Ok(params) => params,
+                Err(e) => return log(&e.to_string()),
+            };
+            let js_args = to_value(&params).unwrap();
             spawn_local(async move {
                 let withdrawn_res = invoke("withdraw", js_args).await;
 
@@ -98,7 +115,14 @@ pub fn app() -> Html {
             {unshielded_accounts.iter().map(|UnShieldedAccountState { address, balance }| {
               html! {
                 <div class="accounts-item">
-                  <UnShieldedAccount address={address.clone()} balance={balance} deposit_clicked={deposit_click.clone()} />
+                  <UnShieldedAccount
+                      address={address.clone()}
+                      balance={balance}
+                      denominations={(*denominations).clone()}
+                      denomination={*denomination}
+                      denomination_selected={denomination_selected.clone()}
+                      deposit_clicked={deposit_click.clone()}
+                  />
                 </div>
               }
             }).collect::<Html>()}
@@ -106,13 +130,13 @@ pub fn app() -> Html {
 
           <h1 class="accounts-title">{"Shielded accounts"}</h1>
           <div class="accounts-list">
-            {shielded_accounts.iter().map(|ShieldedAccountState {id, address, withdraw_success, nullifier }| {
+            {shielded_accounts.iter().map(|ShieldedAccountState {id, address, denomination, withdraw_success, nullifier }| {
               html! {
                 <div class="accounts-item">
                     <ShieldedAccount
                         id={id}
                         address={address.clone()}
-                        deposit_amount={*deposit_amount.deref()}
+                        denomination={denomination}
                         withdraw_success={withdraw_success}
                         withdraw_clicked={withdraw_click.clone()}
                         nullifier = {nullifier.clone()}
@@ -130,6 +154,9 @@ pub fn unshielded_account(
     UnShieldAccountProps {
         address,
         balance,
+        denominations,
+        denomination,
+        denomination_selected,
         deposit_clicked,
     }: &UnShieldAccountProps,
 ) -> Html {
@@ -146,6 +173,18 @@ pub fn unshielded_account(
         })
     };
 
+    // Handle denomination selection
+    let on_denomination_change = {
+        let denomination_selected = denomination_selected.clone();
+        Callback::from(move |e: Event| {
+            if let Some(select) = e.target_dyn_into::<web_sys::HtmlSelectElement>() {
+                if let Ok(selected) = select.value().parse::<u64>() {
+                    denomination_selected.emit(selected);
+                }
+            }
+        })
+    };
+
     // Handle deposit button click
     let on_click = {
         let deposit_clicked = deposit_clicked.clone();
@@ -170,6 +209,17 @@ pub fn unshielded_account(
                     value={shielded_address.to_string()}
                 />
             </div>
+            <div>
+                <select id="denomination" onchange={on_denomination_change}>
+                    {denominations.iter().map(|amount| {
+                        html! {
+                            <option value={amount.to_string()} selected={amount == denomination}>
+                                {*amount}{" ETH"}
+                            </option>
+                        }
+                    }).collect::<Html>()}
+                </select>
+            </div>
             <div class = "deposit-button">
                 <button onclick={on_click} >
                     {"Deposit"}
@@ -184,7 +234,7 @@ pub fn shielded_account(
     ShieldAccountProps {
         id,
         address,
-        deposit_amount,
+        denomination,
         withdraw_success,
         withdraw_clicked,
         nullifier,
@@ -194,16 +244,17 @@ pub fn shielded_account(
     let on_click = {
         let withdraw_clicked = withdraw_clicked.clone();
         let id = *id;
+        let denomination = *denomination;
         let nullifier = nullifier.clone();
         Callback::from(move |_| {
-            withdraw_clicked.emit((id, nullifier.clone()));
+            withdraw_clicked.emit((id, nullifier.clone(), denomination));
         })
     };
 
     html! {
         <div>
             <div>
-                {address.clone()}{" : "}{*deposit_amount}<strong>{" ETH"}</strong>
+                {address.clone()}{" : "}{*denomination}<strong>{" ETH"}</strong>
             </div>
             <div class = "withdraw-button">
                 <button onclick={on_click} disabled={*withdraw_success} >
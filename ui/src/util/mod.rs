@@ -1,5 +1,5 @@
 use serde::Serialize;
-use shield_circuit::Hash;
+use shield_circuit::{Error, Hash};
 use yew::{Callback, Properties};
 
 #[derive(Debug, Clone)]
@@ -18,15 +18,23 @@ impl UnShieldedAccountState {
 pub struct ShieldedAccountState {
     pub id: usize,
     pub address: String,
+    pub denomination: u64,
     pub withdraw_success: bool,
     pub nullifier: String,
 }
 
 impl ShieldedAccountState {
-    pub fn new(id: usize, address: String, withdraw_success: bool, nullifier: String) -> Self {
+    pub fn new(
+        id: usize,
+        address: String,
+        denomination: u64,
+        withdraw_success: bool,
+        nullifier: String,
+    ) -> Self {
         Self {
             id,
             address,
+            denomination,
             withdraw_success,
             nullifier,
         }
@@ -37,6 +45,9 @@ impl ShieldedAccountState {
 pub struct UnShieldAccountProps {
     pub address: String,
     pub balance: u64,
+    pub denominations: Vec<u64>,
+    pub denomination: u64,
+    pub denomination_selected: Callback<u64>,
     pub deposit_clicked: Callback<String>,
 }
 
@@ -44,31 +55,47 @@ pub struct UnShieldAccountProps {
 pub struct ShieldAccountProps {
     pub id: usize,
     pub address: String,
-    pub deposit_amount: u64,
+    pub denomination: u64,
     pub withdraw_success: bool,
     pub nullifier: String,
-    pub withdraw_clicked: Callback<(usize, String)>,
+    pub withdraw_clicked: Callback<(usize, String, u64)>,
 }
 
 #[derive(Serialize)]
 pub struct DepositParams {
-    pub(crate) recipiant: u64,
+    pub(crate) denomination: u64,
+    pub(crate) asset: u64,
 }
 
 #[derive(Serialize)]
 pub struct WithdrawParams {
     pub(crate) nullifier: Hash,
+    pub(crate) denomination: u64,
+    pub(crate) recipient: u64,
+    pub(crate) relayer: u64,
+    pub(crate) fee: u64,
 }
 
 #[derive(Serialize)]
 pub struct GetBalanceParams {
     pub(crate) account: u64,
+    pub(crate) asset: u64,
 }
 
 impl WithdrawParams {
-    pub fn from_hex_str(nullifier: String) -> Self {
-        Self {
-            nullifier: Hash::from_hex(nullifier),
-        }
+    /// Parameters of a withdrawal from the `denomination` pool to
+    /// `recipient` submitted by the wallet itself, without a relayer fee.
+    pub fn from_hex_str(
+        nullifier: String,
+        denomination: u64,
+        recipient: u64,
+    ) -> Result<Self, Error> {
+        Ok(Self {
+            nullifier: Hash::from_hex(nullifier)?,
+            denomination,
+            recipient,
+            relayer: 0,
+            fee: 0,
+        })
     }
 }